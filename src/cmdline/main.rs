@@ -1,19 +1,59 @@
 use simcastle_core;
 
-fn prompt_restore() -> bool {
+#[path = "../common/flags.rs"]
+mod flags;
+use flags::parse_flag;
+
+// Returns whether to restore, and the name of the save.
+fn prompt_restore() -> (bool, String) {
     loop {
-        let user_input = get_input_line("(n)ew or (r)estore? ");
-        if user_input.len() == 0 { continue }
+        let user_input = get_input_line("(n)ew <save> or (r)estore <save>? ");
+        if user_input.len() != 2 { continue }
+
+        if user_input[0] == "r" { return (true, user_input[1].clone()); }
+        if user_input[0] == "n" { return (false, user_input[1].clone()); }
+    }
+}
+
+fn default_save_dir() -> std::path::PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => return std::path::PathBuf::from(home).join(".simcastle").join("saves"),
+        None => return std::env::temp_dir().join("simcastle"),
+    }
+}
 
-        if user_input[0] == "r" { return true; }
-        if user_input[0] == "n" { return false; }
+fn print_saves(slots: &simcastle_core::saveslots::SaveSlots) {
+    let saves = slots.list().expect("list saves");
+    if saves.is_empty() {
+        println!("No saves in {:?}", slots.dir());
+        return;
+    }
+    println!("Saves in {:?}:", slots.dir());
+    for save in saves {
+        match save.metadata {
+            Some(m) => println!("  {}: turn {}, population {}, last played {}",
+                                save.name, m.turn, m.population, m.last_played),
+            None => println!("  {}: (no metadata)", save.name),
+        }
     }
 }
 
-fn begin_game() -> simcastle_core::gamestate::GameState {
+fn begin_game(slots: &simcastle_core::saveslots::SaveSlots,
+              name: &str,
+              seed: Option<u64>,
+              save_format: simcastle_core::logformat::LogFormat) -> anyhow::Result<simcastle_core::gamestate::GameState> {
+    if slots.exists(name)? {
+        return Err(anyhow::anyhow!("There is already a save called {}", name));
+    }
+
+    let seed = seed.unwrap_or_else(|| rand::random());
+    println!("Seed: {} (replay with --seed {})", seed, seed);
+
     let spec = simcastle_core::gamestate::GameSpec{
         initial_potential_characters: 6,
         initial_characters: 3,
+        seed: seed,
+        save_format: save_format,
     };
 
     let setup = simcastle_core::initialsetup::InitialSetup::new(spec);
@@ -32,13 +72,33 @@ fn begin_game() -> simcastle_core::gamestate::GameState {
         }
     }
 
-    let save_file = std::fs::File::create("/tmp/simcastle.save").expect("creating save file");
-    return setup.begin(team, save_file).expect("TODO");
+    return slots.create(name, setup, team);
 }
 
-fn restore_game() -> simcastle_core::gamestate::GameState {
-    return simcastle_core::gamestate::GameState::restore(
-        "/tmp/simcastle.save").expect("TODO");
+fn restore_game(slots: &simcastle_core::saveslots::SaveSlots,
+                name: &str,
+                at_turn: Option<i32>) -> anyhow::Result<simcastle_core::gamestate::GameState> {
+    match at_turn {
+        Some(turn) => {
+            println!("Viewing turn {}; changes won't be saved unless you 'fork <save>'.", turn);
+            return simcastle_core::gamestate::GameState::restore_at_turn(slots.save_path(name)?, turn);
+        },
+        None => return slots.open(name),
+    }
+}
+
+// Announces things that happen during a turn, as they happen.
+fn add_observers(game: &mut simcastle_core::gamestate::GameState) {
+    use simcastle_core::gamestate::MutationT;
+    use simcastle_core::statemachine::Event;
+
+    game.add_observer(Box::new(|event, _state| {
+        match event {
+            Event::Applied(MutationT::CompleteInfrastructure{infra}) => println!("Completed {:?}", infra),
+            Event::Reverted(MutationT::CompleteInfrastructure{infra}) => println!("Un-completed {:?}", infra),
+            _ => {},
+        }
+    }));
 }
 
 fn log_level_as_letter(level: log::Level) -> String {
@@ -51,7 +111,38 @@ fn log_level_as_letter(level: log::Level) -> String {
     }
 }
 
+fn parse_durability(durability_str: &str) -> simcastle_core::statemachine::Durability {
+    match durability_str {
+        "none" => return simcastle_core::statemachine::Durability::None,
+        "flush" => return simcastle_core::statemachine::Durability::FlushPerEntry,
+        "sync" => return simcastle_core::statemachine::Durability::SyncOnCommit,
+        _ => panic!("Unknown durability: {} (expected none, flush or sync)", durability_str),
+    }
+}
+
+fn parse_format(format_str: &str) -> simcastle_core::logformat::LogFormat {
+    match format_str {
+        "json" => return simcastle_core::logformat::LogFormat::Json,
+        "cbor" => return simcastle_core::logformat::LogFormat::Cbor,
+        _ => panic!("Unknown format: {} (expected json or cbor)", format_str),
+    }
+}
+
 fn main() {
+    let seed = parse_flag::<u64>("--seed");
+    let at_turn = parse_flag::<i32>("--at-turn");
+    let durability = parse_flag::<String>("--durability").map(|d| parse_durability(&d));
+    // 0 turns snapshots off.
+    let snapshot_every = parse_flag::<usize>("--snapshot-every");
+    let background_save = std::env::args().any(|a| a == "--background-save");
+    let format = parse_flag::<String>("--format").map(|f| parse_format(&f)).unwrap_or_default();
+    let convert_to = parse_flag::<String>("--convert");
+    if let Some(traits_file) = parse_flag::<std::path::PathBuf>("--traits") {
+        simcastle_core::traits::load(&traits_file).expect("loading --traits");
+    }
+    let slots = simcastle_core::saveslots::SaveSlots::new(
+        parse_flag::<std::path::PathBuf>("--save-dir").unwrap_or_else(default_save_dir));
+
     fern::Dispatch::new()
         .format(|out, msg, record| {
             out.finish(format_args!("{} [{}:{:<3}] {}",
@@ -65,11 +156,43 @@ fn main() {
         .apply()
         .expect("Setting up logging.");
 
-    let mut game: simcastle_core::gamestate::GameState = if prompt_restore() {
-        restore_game()
-    } else {
-        begin_game()
+    // Writes a copy of the save in --format, e.g. to read a CBOR save.
+    if let Some(convert_to) = convert_to {
+        let name = parse_flag::<String>("--save").expect("--convert requires --save <name>");
+        let entries = simcastle_core::gamestate::GameState::convert_save(
+            slots.save_path(&name).expect("save_path"), &convert_to, format).expect("convert_save");
+        println!("Wrote {} entries to {} as {:?}", entries, convert_to, format);
+        return;
+    }
+
+    print_saves(&slots);
+    // The save that the game is being recorded in, if any.
+    let mut save: Option<String>;
+    let mut game: simcastle_core::gamestate::GameState = loop {
+        let (restore, name) = prompt_restore();
+        let game = if restore {
+            restore_game(&slots, &name, at_turn)
+        } else {
+            begin_game(&slots, &name, seed, format)
+        };
+        match game {
+            Ok(game) => {
+                save = if restore && at_turn.is_some() { None } else { Some(name) };
+                break game;
+            },
+            Err(err) => println!("{}", err),
+        }
     };
+    if background_save {
+        game.save_in_background();
+    }
+    if let Some(durability) = durability {
+        game.set_durability(durability);
+    }
+    if let Some(every_n_deltas) = snapshot_every {
+        game.set_snapshot_interval(if every_n_deltas == 0 { None } else { Some(every_n_deltas) });
+    }
+    add_observers(&mut game);
     print_workforce(&game);
     print_state(&game);
 
@@ -86,16 +209,79 @@ fn main() {
             "ef" | "explain food" => print_food(&game),
             "eb" | "explain builder" => print_builder(&game),
             "assign" => set_assignment(&input_array, &mut game),
+            "lead" => set_leader(&input_array, &mut game),
             "b" | "build" => build(&input_array, &mut game),
             "bq" | "buildqueue" => print_build_queue(&game),
+            "u" | "undo" => match game.undo() {
+                Ok(cmd) => println!("Undid: {:?}", cmd),
+                Err(err) => println!("Can't undo: {}", err),
+            },
+            "fork" => {
+                if input_array.len() != 2 {
+                    println!("Invalid fork: fork <save>");
+                    continue;
+                }
+                match slots.fork(&input_array[1], &game) {
+                    Ok(forked) => {
+                        println!("Now saving to {}", input_array[1]);
+                        game = forked;
+                        if background_save {
+                            game.save_in_background();
+                        }
+                        add_observers(&mut game);
+                        save = Some(input_array[1].clone());
+                    },
+                    Err(err) => println!("Can't fork: {}", err),
+                }
+            },
+            "saves" => print_saves(&slots),
+            "copy" | "rename" => {
+                if input_array.len() != 3 {
+                    println!("Invalid {}: {} <from> <to>", input_array[0], input_array[0]);
+                    continue;
+                }
+                if input_array[0] == "rename" && save.as_ref() == Some(&input_array[1]) {
+                    println!("Can't rename the save that's being played");
+                    continue;
+                }
+                let result = if input_array[0] == "copy" {
+                    slots.copy(&input_array[1], &input_array[2])
+                } else {
+                    slots.rename(&input_array[1], &input_array[2])
+                };
+                if let Err(err) = result {
+                    println!("Can't {}: {}", input_array[0], err);
+                }
+            },
+            "delete" => {
+                if input_array.len() != 2 {
+                    println!("Invalid delete: delete <save>");
+                    continue;
+                }
+                if save.as_ref() == Some(&input_array[1]) {
+                    println!("Can't delete the save that's being played");
+                    continue;
+                }
+                if let Err(err) = slots.delete(&input_array[1]) {
+                    println!("Can't delete: {}", err);
+                }
+            },
+            "redo" => match game.redo() {
+                Ok(cmd) => println!("Redid: {:?}", cmd),
+                Err(err) => println!("Can't redo: {}", err),
+            },
             "t" | "turn" => {
                 let prompts = game.advance_turn().expect("advance_turn");
                 handle_prompts(&mut game, prompts);
                 print_state(&game);
+                if let Some(save) = &save {
+                    slots.record(save, &game).expect("record save metadata");
+                }
             }
             _ => println!("Unknown command: {}", input_array.join(" ")),
         }
     }
+    game.close().expect("saving game");
 }
 
 fn get_input_line(prompt: &str) -> Vec<String> {
@@ -134,6 +320,34 @@ fn handle_prompts(state: &mut simcastle_core::gamestate::GameState, prompts: Vec
                     println!("'y' or 'n'");
                 }
             },
+            simcastle_core::gamestate::Prompt::FoodShortage{turns_starving} => {
+                println!("There wasn't enough food to go around ({} turns in a row)!", turns_starving);
+            },
+            simcastle_core::gamestate::Prompt::FoodRunningLow{turns_left} => {
+                println!("Food will run out in {} turns.", turns_left);
+            },
+            simcastle_core::gamestate::Prompt::CharacterDied(c, reason) => match reason {
+                simcastle_core::gamestate::DepartureReason::OldAge =>
+                    println!("{} has died of old age, aged {}.", c.name(), c.age()),
+                _ => println!("{} has starved to death.", c.name()),
+            },
+            simcastle_core::gamestate::Prompt::CharacterLeft(c, reason) => match reason {
+                simcastle_core::gamestate::DepartureReason::LeftUnhappy =>
+                    println!("{} was too unhappy to stay, and has left the castle.", c.name()),
+                _ => println!("{} has left the castle in search of food.", c.name()),
+            },
+            simcastle_core::gamestate::Prompt::HouseholdFormed(a, b) => {
+                println!("{} and {} have set up a household together.", a.name(), b.name());
+            },
+            simcastle_core::gamestate::Prompt::Born(c) => {
+                println!("A child has been born: {}", c.full_debug_string());
+            },
+            simcastle_core::gamestate::Prompt::CameOfAge(c) => {
+                println!("{} has come of age, and can now be assigned work.", c.name());
+            },
+            simcastle_core::gamestate::Prompt::Unrest{average_morale} => {
+                println!("The castle is restless (average morale {}).", average_morale);
+            },
         }
     }
 }
@@ -149,11 +363,42 @@ fn set_assignment(args: &Vec<String>, game: &mut simcastle_core::gamestate::Game
     let job = parse_job(&args[2]);
 
     char_id.map(|char_id| { job.map(|job| {
-        println!("Making character {} into a {:?}", char_id, job);
-        game.execute_command(&simcastle_core::gamestate::UserCommand::AssignToTeam{cid: char_id, job: job}).expect("execute_command");
+        match game.execute_command(&simcastle_core::gamestate::UserCommand::AssignToTeam{cid: char_id, job: job}) {
+            Ok(()) => println!("Making character {} into a {:?}", char_id, job),
+            Err(err) => println!("Can't assign: {}", err),
+        }
     })});
 }
 
+fn set_leader(args: &Vec<String>, game: &mut simcastle_core::gamestate::GameState) {
+    assert_eq!(args[0], "lead");
+    if args.len() != 3 {
+        println!("Invalid lead: lead <job> <char_id|none>");
+        return;
+    }
+
+    let job = match parse_job(&args[1]) {
+        Some(job) => job,
+        None => return,
+    };
+    let char_id = if args[2] == "none" {
+        None
+    } else {
+        match parse_character_id(&args[2]) {
+            Some(char_id) => Some(char_id),
+            None => return,
+        }
+    };
+
+    match game.execute_command(&simcastle_core::gamestate::UserCommand::SetTeamLeader{job: job, cid: char_id}) {
+        Ok(()) => match char_id {
+            Some(char_id) => println!("Character {} now leads the {:?}s", char_id, job),
+            None => println!("The {:?}s no longer have a leader", job),
+        },
+        Err(err) => println!("Can't set leader: {}", err),
+    }
+}
+
 fn build(args: &Vec<String>, game: &mut simcastle_core::gamestate::GameState) {
 //    assert_eq!(args[0], "assign");
     if args.len() != 2 {
@@ -203,6 +448,10 @@ fn print_teams(game: &simcastle_core::gamestate::GameState) {
             println!();
         }
         println!("== {:?} ==", job);
+        match team.leader().and_then(|cid| game.population().character_with_id(cid)) {
+            Some(leader) => println!("Leader: {}", leader.name()),
+            None => println!("Leader: none"),
+        }
         for char_id in team.members() {
             let c = game.population().character_with_id(char_id.clone());
             println!("{:?}", c.unwrap().full_debug_string());
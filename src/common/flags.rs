@@ -0,0 +1,11 @@
+// Command line helpers shared by the binaries.
+
+// The value following 'name' on the command line, e.g. parse_flag::<u64>("--seed").
+// None if the flag isn't there; panics if it has no value, or one that doesn't
+// parse.
+pub fn parse_flag<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let args = std::env::args().collect::<Vec<String>>();
+    let pos = args.iter().position(|a| a == name)?;
+    let value_str = args.get(pos + 1).expect(&format!("{} requires a value", name));
+    return Some(value_str.parse::<T>().ok().expect(&format!("Could not parse {}: {}", name, value_str)));
+}
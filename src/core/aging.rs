@@ -0,0 +1,103 @@
+use super::character;
+use super::population;
+
+use rand::Rng;
+
+// Everyone gets a year older every TURNS_PER_YEAR turns.
+pub const TURNS_PER_YEAR: i32 = 4;
+
+// Past this age, each trait's capacity drops by up to MAX_YEARLY_DECLINE a
+// year, dragging its value down with it.
+const DECLINE_AGE: i32 = 50;
+const MAX_YEARLY_DECLINE: i32 = 3;
+
+// Past this age, the chance of dying each year starts at
+// YEARLY_DEATH_RISK_INCREASE and grows by as much again every year.
+const DEATH_RISK_AGE: i32 = 60;
+const YEARLY_DEATH_RISK_INCREASE: f64 = 0.05;
+
+pub struct Consequences {
+    pub character_deltas: Vec<character::CharacterDelta>,
+    pub died: Vec<character::CharacterId>,
+}
+
+pub fn is_end_of_year(turn: i32) -> bool {
+    return (turn + 1) % TURNS_PER_YEAR == 0;
+}
+
+pub fn end_of_year<R: Rng + ?Sized>(population: &population::Population, rng: &mut R) -> Consequences {
+    let mut consequences = Consequences{character_deltas: vec![], died: vec![]};
+    for c in population.characters() {
+        let age = c.age() + 1;
+        let mut delta = character::CharacterDelta{
+            id: c.id(),
+            changed_trait_values: maplit::hashmap!{},
+            changed_trait_capacities: maplit::hashmap!{},
+            health: None,
+            morale: None,
+            age: Some(age),
+        };
+
+        if age > DECLINE_AGE {
+            // Visit traits in a fixed order so the same rng produces the same deltas.
+            for t in character::Trait::iter() {
+                let current = c.get_trait_desc(t);
+                let capacity = std::cmp::max(0, current.capacity - rng.gen_range(0, MAX_YEARLY_DECLINE + 1));
+                if capacity != current.capacity {
+                    delta.changed_trait_capacities.insert(t, capacity);
+                }
+                if current.value > capacity {
+                    delta.changed_trait_values.insert(t, capacity);
+                }
+            }
+        }
+
+        if age > DEATH_RISK_AGE {
+            let risk = ((age - DEATH_RISK_AGE) as f64 * YEARLY_DEATH_RISK_INCREASE).min(1.0);
+            if rng.gen_bool(risk) {
+                consequences.died.push(c.id());
+            }
+        }
+        consequences.character_deltas.push(delta);
+    }
+    return consequences;
+}
+
+#[cfg(test)]
+mod aging_tests {
+    use crate::character::{Character, CharacterId, Trait};
+    use crate::population::Population;
+    use crate::types;
+
+    #[test]
+    fn old_age() {
+        let mut rng = types::new_rng(2);
+        let mut young = Character::new_random(CharacterId(0), &mut rng);
+        young.set_age(20);
+        let mut old = Character::new_random(CharacterId(1), &mut rng);
+        old.set_age(75);
+        let old_strength = old.get_trait_desc(Trait::STRENGTH).capacity;
+        let population = Population::new(vec![young, old]);
+
+        let mut years = 0;
+        let mut strength = old_strength;
+        loop {
+            let consequences = super::end_of_year(&population, &mut rng);
+            assert_eq!(2, consequences.character_deltas.len());
+            assert_eq!(Some(21), consequences.character_deltas[0].age);
+            assert!(consequences.character_deltas[0].changed_trait_capacities.is_empty());
+            strength = std::cmp::min(strength, *consequences.character_deltas[1].changed_trait_capacities
+                                     .get(&Trait::STRENGTH).unwrap_or(&old_strength));
+            if consequences.died == vec![CharacterId(1)] {
+                break;
+            }
+            assert!(consequences.died.is_empty());
+            years += 1;
+            assert!(years < 100, "a 76 year old should eventually die");
+        }
+        assert!(strength < old_strength);
+
+        assert!(!super::is_end_of_year(0));
+        assert!(super::is_end_of_year(super::TURNS_PER_YEAR - 1));
+    }
+}
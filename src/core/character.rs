@@ -6,25 +6,10 @@ extern crate std;
 use log::{debug};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use strum::IntoEnumIterator;
-use strum_macros::EnumIter;
-
-#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize, EnumIter)]
-pub enum Trait {
-    Intelligence,
-    Strength,
-    WorkEthic,
-}
 
-impl Trait {
-    pub fn string3(&self) -> &str {
-        match self {
-            Trait::Intelligence => "INT",
-            Trait::Strength => "STR",
-            Trait::WorkEthic => "WOR",
-        }
-    }
-}
+use super::traits;
+use super::workforce;
+pub use super::traits::Trait;
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
 pub struct CharacterId(pub i64);
@@ -42,25 +27,82 @@ pub struct TraitRating {
     pub capacity: i32,
 }
 
+pub const MAX_HEALTH: i32 = 100;
+pub const INITIAL_MORALE: i32 = 50;
+// Characters start out somewhere between ADULT_AGE and MAX_INITIAL_AGE.
+pub const ADULT_AGE: i32 = 16;
+const MAX_INITIAL_AGE: i32 = 45;
+
+// How far a child's trait capacities stray from the average of their parents'.
+const INHERITED_CAPACITY_STDDEV: f32 = 5.0;
+
+// Skills are in [0, MAX_SKILL]. Each turn working a job closes
+// 1/SKILL_GAIN_DIVISOR of the gap to MAX_SKILL (but always gains at least a
+// point), and each turn away from it loses SKILL_DECAY_PER_TURN.
+pub const MAX_SKILL: i32 = 100;
+const SKILL_GAIN_DIVISOR: i32 = 20;
+const SKILL_DECAY_PER_TURN: i32 = 1;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Character {
     id: CharacterId,
     name: String,
     traits: std::collections::HashMap<Trait, TraitRating>,
+    // In [0, MAX_HEALTH]; a character whose health reaches 0 dies.
+    health: i32,
+    // In [0, 100], 50 is content.
+    morale: i32,
+    // In years.
+    age: i32,
+    // Experience at each job the character has ever done.
+    skills: std::collections::HashMap<workforce::Job, i32>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct CharacterDelta {
     pub id: CharacterId,
     pub changed_trait_values: std::collections::HashMap<Trait, i32>,
+    pub changed_trait_capacities: std::collections::HashMap<Trait, i32>,
+    // New values, if they changed.
+    pub health: Option<i32>,
+    pub morale: Option<i32>,
+    pub age: Option<i32>,
 }
 
 impl Character {
-    pub fn new_random(id: CharacterId) -> Character {
+    pub fn new_random<R: Rng + ?Sized>(id: CharacterId, rng: &mut R) -> Character {
+        return Character{
+            id: id,
+            name: random_name(rng),
+            traits: random_traits(rng),
+            health: MAX_HEALTH,
+            morale: INITIAL_MORALE,
+            age: rng.gen_range(ADULT_AGE, MAX_INITIAL_AGE + 1),
+            skills: std::collections::HashMap::new(),
+        };
+    }
+
+    // A newborn, whose trait capacities are a blend of its parents'.
+    pub fn new_child<R: Rng + ?Sized>(id: CharacterId, parents: (&Character, &Character), rng: &mut R) -> Character {
+        let mut traits = std::collections::HashMap::new();
+        // Visit traits in a fixed order so the same rng produces the same child.
+        for t in Trait::iter() {
+            let noise: f32 = rng.sample(rand_distr::StandardNormal);
+            let inherited = (parents.0.get_trait_desc(t).capacity + parents.1.get_trait_desc(t).capacity) / 2;
+            let capacity = std::cmp::max(0, std::cmp::min(100, inherited + (INHERITED_CAPACITY_STDDEV * noise) as i32));
+            let mut rating = random_stat(&t.definition(), rng);
+            rating.value = std::cmp::max(0, capacity - (rating.capacity - rating.value));
+            rating.capacity = capacity;
+            traits.insert(t, rating);
+        }
         return Character{
             id: id,
-            name: random_name(),
-            traits: random_traits(),
+            name: random_name(rng),
+            traits: traits,
+            health: MAX_HEALTH,
+            morale: INITIAL_MORALE,
+            age: 0,
+            skills: std::collections::HashMap::new(),
         };
     }
 
@@ -73,15 +115,66 @@ impl Character {
     }
 
     pub fn get_trait_value(&self, t: Trait) -> i32 {
-        return self.traits.get(&t).expect("unexpected character trait").value;
+        return self.get_trait_desc(t).value;
     }
 
-    pub fn get_trait_desc(&self, t: Trait) -> &TraitRating {
-        return self.traits.get(&t).expect("unexpected character trait");
+    // Characters from before a trait was loaded are average at it.
+    pub fn get_trait_desc(&self, t: Trait) -> TraitRating {
+        return self.traits.get(&t).cloned().unwrap_or_else(|| t.definition().average_rating());
     }
 
     pub fn mut_trait(&mut self, t: Trait) -> &mut TraitRating {
-        return self.traits.get_mut(&t).expect("unexpected character trait");
+        return self.traits.entry(t).or_insert_with(|| t.definition().average_rating());
+    }
+
+    pub fn health(&self) -> i32 {
+        return self.health;
+    }
+
+    pub fn set_health(&mut self, health: i32) {
+        self.health = health;
+    }
+
+    pub fn morale(&self) -> i32 {
+        return self.morale;
+    }
+
+    pub fn set_morale(&mut self, morale: i32) {
+        self.morale = morale;
+    }
+
+    pub fn age(&self) -> i32 {
+        return self.age;
+    }
+
+    pub fn set_age(&mut self, age: i32) {
+        self.age = age;
+    }
+
+    pub fn is_adult(&self) -> bool {
+        return self.age >= ADULT_AGE;
+    }
+
+    pub fn skill(&self, job: workforce::Job) -> i32 {
+        return self.skills.get(&job).cloned().unwrap_or(0);
+    }
+
+    pub fn skills(&self) -> &std::collections::HashMap<workforce::Job, i32> {
+        return &self.skills;
+    }
+
+    // Practices 'job' (if any) for a turn, and gets rusty at everything else.
+    pub fn work_turn(&mut self, job: Option<workforce::Job>) {
+        for (j, skill) in self.skills.iter_mut() {
+            if Some(*j) != job {
+                *skill = std::cmp::max(0, *skill - SKILL_DECAY_PER_TURN);
+            }
+        }
+        if let Some(job) = job {
+            let skill = self.skills.entry(job).or_insert(0);
+            *skill = std::cmp::min(MAX_SKILL, *skill + std::cmp::max(1, (MAX_SKILL - *skill) / SKILL_GAIN_DIVISOR));
+        }
+        self.skills.retain(|_, skill| *skill > 0);
     }
 
     pub fn full_debug_string(&self) -> String {
@@ -89,12 +182,17 @@ impl Character {
             let t_desc = self.get_trait_desc(t);
             return format!("{}:{}/{}", t.string3(), t_desc.value, t_desc.capacity);
         }).collect::<Vec<String>>().join(" ");
-        return format!("[{:03}|{:10}] {}", self.id.0, self.name, traits_str);
+        let mut skills = self.skills.iter().map(|(j, s)| format!(" {:?}:{}", j, s)).collect::<Vec<String>>();
+        skills.sort();
+        return format!("[{:03}|{:10}] AGE:{} {} HP:{} MOR:{}{}",
+                       self.id.0, self.name, self.age, traits_str, self.health, self.morale, skills.concat());
     }
 
-    pub fn compute_end_of_turn_delta(&self) -> Option<CharacterDelta> {
+    pub fn compute_end_of_turn_delta<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<CharacterDelta> {
         let mut deltas: std::collections::HashMap<Trait, i32> = maplit::hashmap!{};
-        for (t, current) in &self.traits {
+        // Visit traits in a fixed order so the same rng produces the same deltas.
+        for t in Trait::iter() {
+            let current = self.get_trait_desc(t);
             let mut inc = 0;
             loop {
                 if current.capacity == current.value { break }
@@ -103,7 +201,7 @@ impl Character {
                 // TODO tweak weights
                 // TODO non-linear probabiliy?
 //                println!("prob = {} = 1 / (({} - {} - {}) * 10)", prob, current.capacity, current.value, inc);
-                if rand::thread_rng().gen_bool(prob) {
+                if rng.gen_bool(prob) {
                     inc = inc + 1
                 } else {
                     break;
@@ -111,24 +209,31 @@ impl Character {
             }
             if inc > 0 {
                 debug!("Trait change: cid={} trait={} delta={} v={}", self.id, t.string3(), inc, current.value + inc);
-                deltas.insert(*t, current.value + inc);
+                deltas.insert(t, current.value + inc);
             }
         }
 
         if deltas.len() > 0 {
-            return Some(CharacterDelta{id: self.id, changed_trait_values: deltas});
+            return Some(CharacterDelta{
+                id: self.id,
+                changed_trait_values: deltas,
+                changed_trait_capacities: maplit::hashmap!{},
+                health: None,
+                morale: None,
+                age: None,
+            });
         } else {
             return None;
         }
     }
 }
 
-fn random_stat() -> TraitRating {
-    let cap_z_score: f32 = rand::thread_rng().sample(rand_distr::StandardNormal);
-    let headroom_z_score: f32 = rand::thread_rng().sample(rand_distr::StandardNormal);
+fn random_stat<R: Rng + ?Sized>(def: &traits::TraitDefinition, rng: &mut R) -> TraitRating {
+    let cap_z_score: f32 = rng.sample(rand_distr::StandardNormal);
+    let headroom_z_score: f32 = rng.sample(rand_distr::StandardNormal);
 
-    let capacity = 55 + (10.0 * cap_z_score) as i32;
-    let headroom = 10 + (5.0 * headroom_z_score) as i32;
+    let capacity = def.capacity_mean + (def.capacity_stddev * cap_z_score) as i32;
+    let headroom = def.headroom_mean + (def.headroom_stddev * headroom_z_score) as i32;
 
     return TraitRating{
         capacity: capacity,
@@ -136,16 +241,15 @@ fn random_stat() -> TraitRating {
     };
 }
 
-fn random_traits() -> std::collections::HashMap<Trait, TraitRating> {
+fn random_traits<R: Rng + ?Sized>(rng: &mut R) -> std::collections::HashMap<Trait, TraitRating> {
     let mut map: std::collections::HashMap<Trait, TraitRating> = std::collections::HashMap::new();
     for t in Trait::iter() {
-        map.insert(t.clone(), random_stat());
+        map.insert(t.clone(), random_stat(&t.definition(), rng));
     }
     return map;
 }
 
-fn random_name() -> String {
-    let mut rng = rand::thread_rng();
+fn random_name<R: Rng + ?Sized>(rng: &mut R) -> String {
     let names = vec![
         "Alpha", "Bravo", "Charlie", "Delta", "Echo", "Foxtrot", "Golf",
         "Hotel", "India", "Juliet", "Kilo", "Lima", "Mike", "November",
@@ -154,3 +258,48 @@ fn random_name() -> String {
     ];
     return names[rng.gen_range(0, names.len())].to_string();
 }
+
+#[cfg(test)]
+mod character_tests {
+    use super::{Character, CharacterId, Trait, MAX_SKILL};
+    use crate::types;
+    use crate::workforce::Job;
+
+    #[test]
+    fn skills() {
+        let mut c = Character::new_random(CharacterId(0), &mut types::new_rng(0));
+        assert_eq!(0, c.skill(Job::FARMER));
+
+        c.work_turn(Some(Job::FARMER));
+        assert_eq!(5, c.skill(Job::FARMER));
+        for _ in 0..200 {
+            c.work_turn(Some(Job::FARMER));
+        }
+        assert_eq!(MAX_SKILL, c.skill(Job::FARMER));
+
+        c.work_turn(Some(Job::BUILDER));
+        assert_eq!(MAX_SKILL - 1, c.skill(Job::FARMER));
+        assert_eq!(5, c.skill(Job::BUILDER));
+        for _ in 0..10 {
+            c.work_turn(None);
+        }
+        assert_eq!(MAX_SKILL - 11, c.skill(Job::FARMER));
+        assert!(!c.skills().contains_key(&Job::BUILDER));
+    }
+
+    #[test]
+    fn missing_traits() {
+        // As if Leadership had been loaded after the character was created.
+        let mut json = serde_json::to_value(Character::new_random(CharacterId(0), &mut types::new_rng(0))).unwrap();
+        json["traits"].as_object_mut().unwrap().remove("Leadership");
+        let mut c: Character = serde_json::from_value(json).unwrap();
+
+        let average = Trait::LEADERSHIP.definition().average_rating();
+        assert_eq!(average.value, c.get_trait_value(Trait::LEADERSHIP));
+        assert!(c.full_debug_string().contains(&format!("LEA:{}/{}", average.value, average.capacity)));
+        c.compute_end_of_turn_delta(&mut types::new_rng(0));
+
+        c.mut_trait(Trait::LEADERSHIP).value += 1;
+        assert_eq!(average.value + 1, c.get_trait_value(Trait::LEADERSHIP));
+    }
+}
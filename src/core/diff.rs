@@ -0,0 +1,227 @@
+use super::castle;
+use super::character;
+use super::gamestate;
+use super::types;
+use super::workforce;
+
+use serde::Serialize;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Change<T> {
+    pub from: T,
+    pub to: T,
+}
+
+fn change<T: PartialEq + Clone>(from: &T, to: &T) -> Option<Change<T>> {
+    if from == to {
+        return None;
+    }
+    return Some(Change{from: from.clone(), to: to.clone()});
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TraitChange {
+    pub character: character::CharacterId,
+    pub t: character::Trait,
+    pub value: Change<i32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SkillChange {
+    pub character: character::CharacterId,
+    pub job: workforce::Job,
+    pub value: Change<i32>,
+}
+
+// A character moving between teams. None means unassigned.
+#[derive(Clone, Debug, Serialize)]
+pub struct AssignmentChange {
+    pub character: character::CharacterId,
+    pub job: Change<Option<workforce::Job>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RapportChange {
+    pub a: character::CharacterId,
+    pub b: character::CharacterId,
+    pub turns_on_same_team: Change<i32>,
+}
+
+// Everything that differs between two snapshots of a game. Characters that
+// only exist in one of the snapshots are listed as added or removed, and
+// don't show up anywhere else.
+#[derive(Clone, Debug, Serialize)]
+pub struct GameStateDiff {
+    pub turn: Change<i32>,
+    pub food: Option<Change<types::Millis>>,
+    pub added_characters: Vec<character::CharacterId>,
+    pub removed_characters: Vec<character::CharacterId>,
+    pub trait_changes: Vec<TraitChange>,
+    pub skill_changes: Vec<SkillChange>,
+    pub assignment_changes: Vec<AssignmentChange>,
+    pub rapport_changes: Vec<RapportChange>,
+    pub build_queue: Option<Change<Vec<castle::Infrastructure>>>,
+    pub build_progress: Option<Change<types::Millis>>,
+    pub acres_of_farmland: Option<Change<i32>>,
+    pub food_storage: Option<Change<types::Millis>>,
+}
+
+pub fn diff(from: &gamestate::GameStateT, to: &gamestate::GameStateT) -> GameStateDiff {
+    let mut from_ids = from.population.characters().iter().map(|c| c.id()).collect::<Vec<character::CharacterId>>();
+    from_ids.sort();
+    let mut to_ids = to.population.characters().iter().map(|c| c.id()).collect::<Vec<character::CharacterId>>();
+    to_ids.sort();
+    let common_ids = from_ids.iter().filter(|id| to_ids.contains(id)).cloned().collect::<Vec<character::CharacterId>>();
+
+    let mut trait_changes = vec![];
+    let mut skill_changes = vec![];
+    let mut assignment_changes = vec![];
+    for &id in &common_ids {
+        let (before, after) = match (from.population.character_with_id(id), to.population.character_with_id(id)) {
+            (Some(before), Some(after)) => (before, after),
+            _ => continue,
+        };
+        for t in character::Trait::iter() {
+            if let Some(value) = change(&before.get_trait_value(t), &after.get_trait_value(t)) {
+                trait_changes.push(TraitChange{character: id, t: t, value: value});
+            }
+        }
+        let mut jobs = before.skills().keys().chain(after.skills().keys()).cloned().collect::<Vec<workforce::Job>>();
+        jobs.sort_by_key(|j| format!("{:?}", j));
+        jobs.dedup();
+        for job in jobs {
+            if let Some(value) = change(&before.skill(job), &after.skill(job)) {
+                skill_changes.push(SkillChange{character: id, job: job, value: value});
+            }
+        }
+        if let Some(job) = change(&from.workforce.job_of(id), &to.workforce.job_of(id)) {
+            assignment_changes.push(AssignmentChange{character: id, job: job});
+        }
+    }
+
+    let mut rapport_changes = vec![];
+    for (i, &a) in common_ids.iter().enumerate() {
+        for &b in &common_ids[i + 1..] {
+            let turns_on_same_team = change(&from.population.rapport_tracker().turns_on_same_team(&a, &b),
+                                            &to.population.rapport_tracker().turns_on_same_team(&a, &b));
+            if let Some(turns_on_same_team) = turns_on_same_team {
+                rapport_changes.push(RapportChange{a: a, b: b, turns_on_same_team: turns_on_same_team});
+            }
+        }
+    }
+
+    return GameStateDiff{
+        turn: Change{from: from.turn, to: to.turn},
+        food: change(&from.food, &to.food),
+        added_characters: to_ids.iter().filter(|id| !from_ids.contains(id)).cloned().collect(),
+        removed_characters: from_ids.iter().filter(|id| !to_ids.contains(id)).cloned().collect(),
+        trait_changes: trait_changes,
+        skill_changes: skill_changes,
+        assignment_changes: assignment_changes,
+        rapport_changes: rapport_changes,
+        build_queue: change(&from.castle.build_queue.queue, &to.castle.build_queue.queue),
+        build_progress: change(&from.castle.build_queue.progress, &to.castle.build_queue.progress),
+        acres_of_farmland: change(&from.castle.food_infrastructure.acres_of_farmland,
+                                  &to.castle.food_infrastructure.acres_of_farmland),
+        food_storage: change(&from.castle.food_infrastructure.food_storage,
+                             &to.castle.food_infrastructure.food_storage),
+    };
+}
+
+impl GameStateDiff {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        return Ok(serde_json::to_string_pretty(self)?);
+    }
+}
+
+fn job_string(job: &Option<workforce::Job>) -> String {
+    return job.map(|j| format!("{:?}", j)).unwrap_or("unassigned".to_string());
+}
+
+// One change per line.
+impl std::fmt::Display for GameStateDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Turn {} -> {}", self.turn.from, self.turn.to)?;
+        if let Some(food) = &self.food {
+            writeln!(f, "Food: {} -> {}", food.from, food.to)?;
+        }
+        for id in &self.added_characters {
+            writeln!(f, "Character {} joined", id)?;
+        }
+        for id in &self.removed_characters {
+            writeln!(f, "Character {} left", id)?;
+        }
+        for c in &self.trait_changes {
+            writeln!(f, "Character {} {}: {} -> {}", c.character, c.t.string3(), c.value.from, c.value.to)?;
+        }
+        for c in &self.skill_changes {
+            writeln!(f, "Character {} {:?} skill: {} -> {}", c.character, c.job, c.value.from, c.value.to)?;
+        }
+        for c in &self.assignment_changes {
+            writeln!(f, "Character {}: {} -> {}", c.character, job_string(&c.job.from), job_string(&c.job.to))?;
+        }
+        for c in &self.rapport_changes {
+            writeln!(f, "Characters {} and {}: {} -> {} turns on the same team",
+                     c.a, c.b, c.turns_on_same_team.from, c.turns_on_same_team.to)?;
+        }
+        if let Some(queue) = &self.build_queue {
+            writeln!(f, "Build queue: {:?} -> {:?}", queue.from, queue.to)?;
+        }
+        if let Some(progress) = &self.build_progress {
+            writeln!(f, "Build progress: {} -> {}", progress.from, progress.to)?;
+        }
+        if let Some(acres) = &self.acres_of_farmland {
+            writeln!(f, "Acres of farmland: {} -> {}", acres.from, acres.to)?;
+        }
+        if let Some(storage) = &self.food_storage {
+            writeln!(f, "Food storage: {} -> {}", storage.from, storage.to)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use crate::gamestate::{GameSpec, UserCommand};
+    use crate::initialsetup::InitialSetup;
+    use crate::logformat::LogFormat;
+    use crate::workforce::Job;
+
+    #[test]
+    fn one_turn() {
+        let path = std::env::temp_dir().join(format!("diff_test.{}", std::process::id()));
+        let setup = InitialSetup::new(GameSpec{
+            initial_potential_characters: 3,
+            initial_characters: 3,
+            seed: 5,
+            save_format: LogFormat::Json,
+        });
+        let selected = setup.character_candidates.iter().map(|c| c.id()).collect();
+        let mut game = setup.begin(selected, &path).expect("begin");
+        let ids = game.population().characters().iter().map(|c| c.id()).collect::<Vec<_>>();
+
+        let before = game.state().clone();
+        game.execute_command(&UserCommand::AssignToTeam{cid: ids[0], job: Job::FARMER}).expect("assign");
+        game.execute_command(&UserCommand::AssignToTeam{cid: ids[1], job: Job::FARMER}).expect("assign");
+        game.advance_turn().expect("advance_turn");
+
+        let diff = super::diff(&before, game.state());
+        assert_eq!((0, 1), (diff.turn.from, diff.turn.to));
+        assert!(diff.food.is_some());
+        assert_eq!(2, diff.assignment_changes.len());
+        assert_eq!(Some(Job::FARMER), diff.assignment_changes[0].job.to);
+        assert_eq!(1, diff.rapport_changes.len());
+        assert_eq!(2, diff.skill_changes.len());
+        assert!(diff.added_characters.is_empty());
+
+        let text = format!("{}", diff);
+        assert!(text.contains("unassigned -> FARMER"), "{}", text);
+        let json: serde_json::Value = serde_json::from_str(&diff.to_json().expect("to_json")).expect("valid JSON");
+        assert_eq!(1, json["turn"]["to"]);
+
+        let unchanged = super::diff(game.state(), game.state());
+        assert_eq!("Turn 1 -> 1\n", format!("{}", unchanged));
+
+        std::fs::remove_file(&path).expect("cleanup");
+    }
+}
@@ -2,13 +2,13 @@ use super::castle;
 use super::character;
 use super::population;
 use super::team;
+use super::traits;
 use super::types;
+use super::workforce;
 
 use crate::itertools::Itertools;
 
 // Food production model nodes
-// - Leadership
-// - Per-character experience/expertise
 
 #[derive(Clone)]
 pub enum Op { SUM, MULTIPLY }
@@ -80,7 +80,10 @@ fn stringify_exp(e: &TaggedExp, indent: &str) -> String {
     }
 }
 
-fn team_linear_traits_food(weights: &std::collections::HashMap<character::Trait, f32>,
+// How much a worker's production goes up per 10 points of skill at their job.
+const SKILL_WEIGHT: f32 = 0.05;
+
+fn team_linear_traits_food(weights: &[(character::Trait, f32)],
                            team: &team::Team,
                            population: &population::Population,
                            infrastructure: &castle::FoodInfrastructure) -> TaggedExp {
@@ -95,10 +98,11 @@ fn team_linear_traits_food(weights: &std::collections::HashMap<character::Trait,
             tag: "base (constrained by acres_of_farmland)".to_string(),
         }
     };
-    return team_linear_traits_generic(weights, team, population, base_production);
+    return team_linear_traits_generic(weights, workforce::Job::FARMER, team, population, base_production);
 }
 
-fn team_linear_traits_generic(weights: &std::collections::HashMap<character::Trait, f32>,
+fn team_linear_traits_generic(weights: &[(character::Trait, f32)],
+                              job: workforce::Job,
                               team: &team::Team,
                               population: &population::Population,
                               base_production: TaggedExp) -> TaggedExp {
@@ -112,6 +116,11 @@ fn team_linear_traits_generic(weights: &std::collections::HashMap<character::Tra
                 tag: format!("{} ({})", t.string3().to_string(), v),
             });
         }
+        let skill = c.skill(job);
+        character_skills.push(TaggedExp{
+            e: Exp::Constant{v: types::Millis::from_f32(SKILL_WEIGHT * skill as f32 / 10.0)},
+            tag: format!("{:?} skill ({})", job, skill),
+        });
         character_exps.push(TaggedExp{
             e: Exp::ArrayExp{op: Op::SUM, vs: character_skills},
             tag: c.name().to_string(),
@@ -124,7 +133,8 @@ fn team_linear_traits_generic(weights: &std::collections::HashMap<character::Tra
     };
 }
 
-fn cotenure_exp(team: &team::Team, rapport_tracker: &population::RapportTracker) -> TaggedExp {
+// How long the team's members have worked together at 'job'.
+fn cotenure_exp(job: workforce::Job, team: &team::Team, rapport_tracker: &population::RapportTracker) -> TaggedExp {
     let log_base = 100.0;
     let multiplier = 1.0 / 3.0;
 
@@ -138,7 +148,7 @@ fn cotenure_exp(team: &team::Team, rapport_tracker: &population::RapportTracker)
     let mut total_cotenure: i32 = 0;
     let mut num_pairs: i32 = 0;
     for (c1, c2) in team.member_pairs() {
-        total_cotenure += rapport_tracker.turns_on_same_team(&c1, &c2);
+        total_cotenure += rapport_tracker.turns_on_same_team_at(job, &c1, &c2);
         num_pairs += 1;
     }
 
@@ -158,22 +168,102 @@ fn cotenure_exp(team: &team::Team, rapport_tracker: &population::RapportTracker)
     };
 }
 
+// Happy workers work harder: average morale scales production by up to
+// MORALE_EFFECT either way.
+const MORALE_EFFECT: f32 = 0.25;
+
+fn morale_exp(team: &team::Team, population: &population::Population) -> TaggedExp {
+    let morales = team.members().iter()
+        .filter_map(|cid| population.character_with_id(*cid))
+        .map(|c| c.morale())
+        .collect::<Vec<i32>>();
+    if morales.is_empty() {
+        return TaggedExp{
+            e: Exp::Constant{v: types::Millis::from_i32(1)},
+            tag: "No workers".to_string(),
+        };
+    }
+
+    let average_morale = morales.iter().sum::<i32>() as f32 / morales.len() as f32;
+    return TaggedExp{
+        e: Exp::Constant{v: types::Millis::from_f32(1.0 + MORALE_EFFECT * (average_morale - 50.0) / 50.0)},
+        tag: format!("team average morale ({})", average_morale),
+    };
+}
+
+// A harmonious team works better together: each stddev of harmony scales
+// production by HARMONY_EFFECT.
+const HARMONY_EFFECT: f32 = 0.1;
+
+fn harmony_exp(team: &team::Team, population: &population::Population) -> TaggedExp {
+    let harmony = team.harmony(population);
+    let term = |v: f32, tag: String| TaggedExp{
+        e: Exp::Constant{v: types::Millis::from_f32(HARMONY_EFFECT * v)},
+        tag: tag,
+    };
+    let leader = |cid: Option<character::CharacterId>| cid.and_then(|cid| population.character_with_id(cid));
+    let leadership_tag = match (leader(team.leader()), leader(harmony.leader)) {
+        (Some(designated), _) => format!("led by {} (see leader)", designated.name()),
+        (None, Some(natural)) => format!("led by {} ({} {})", natural.name(), character::Trait::LEADERSHIP.string3(),
+                                         natural.get_trait_value(character::Trait::LEADERSHIP)),
+        (None, None) => "leaderless".to_string(),
+    };
+    return TaggedExp{
+        e: Exp::ArrayExp{
+            op: Op::SUM,
+            vs: vec![
+                TaggedExp{e: Exp::Constant{v: types::Millis::from_i32(1)}, tag: "base".to_string()},
+                term(harmony.compatibility, "trait compatibility".to_string()),
+                term(harmony.leadership, leadership_tag),
+            ],
+        },
+        tag: "team harmony".to_string(),
+    };
+}
+
+// A designated leader's traits scale the whole team's production; see
+// traits::leader_weights. An average leader makes no difference.
+fn leader_exp(team: &team::Team, population: &population::Population) -> TaggedExp {
+    let leader = match team.leader().and_then(|cid| population.character_with_id(cid)) {
+        Some(leader) => leader,
+        None => return TaggedExp{
+            e: Exp::Constant{v: types::Millis::from_i32(1)},
+            tag: "No designated leader".to_string(),
+        },
+    };
+
+    let mut terms = vec![TaggedExp{e: Exp::Constant{v: types::Millis::from_i32(1)}, tag: "base".to_string()}];
+    for (t, weight) in traits::leader_weights() {
+        let v = leader.get_trait_value(t);
+        let average = t.definition().average_rating().value;
+        terms.push(TaggedExp{
+            e: Exp::Constant{v: types::Millis::from_f32(weight * (v - average) as f32 / 10.0)},
+            tag: format!("{} ({})", t.string3(), v),
+        });
+    }
+    return TaggedExp{
+        e: Exp::ArrayExp{op: Op::SUM, vs: terms},
+        tag: format!("leader {}", leader.name()),
+    };
+}
+
 fn food_production(team: &team::Team,
                    population: &population::Population,
                    infrastructure: &castle::FoodInfrastructure) -> TaggedExp {
     return TaggedExp{
-        e: Exp::BinaryExp{
+        e: Exp::ArrayExp{
             op: Op::MULTIPLY,
-            v1: Box::new(team_linear_traits_food(
-                &maplit::hashmap!{
-                    // 'x' boost per 10 points (1 stdev) of trait:
-                    character::Trait::Intelligence => 0.05,
-                    character::Trait::WorkEthic => 0.1,
-                },
-                team,
-                population,
-                infrastructure)),
-            v2: Box::new(cotenure_exp(team, population.rapport_tracker())),
+            vs: vec![
+                team_linear_traits_food(
+                    &traits::job_weights(workforce::Job::FARMER),
+                    team,
+                    population,
+                    infrastructure),
+                cotenure_exp(workforce::Job::FARMER, team, population.rapport_tracker()),
+                morale_exp(team, population),
+                harmony_exp(team, population),
+                leader_exp(team, population),
+            ],
         },
         tag: "production".to_string(),
     }
@@ -182,22 +272,23 @@ fn food_production(team: &team::Team,
 fn builder_production(team: &team::Team,
                       population: &population::Population) -> TaggedExp {
     return TaggedExp{
-        e: Exp::BinaryExp{
+        e: Exp::ArrayExp{
             op: Op::MULTIPLY,
-            v1: Box::new(team_linear_traits_generic(
-                &maplit::hashmap!{
-                    // 'x' boost per 10 points (1 stdev) of trait:
-                    character::Trait::Intelligence => 0.05,
-                    character::Trait::WorkEthic => 0.1,
-                    character::Trait::Strength => 0.1,
-                },
-                team,
-                population,
-                TaggedExp{
-                    tag: "base produciton".to_string(),
-                    e: Exp::Constant{v: types::Millis::from_i32(1)},
-                })),
-            v2: Box::new(cotenure_exp(team, population.rapport_tracker())),
+            vs: vec![
+                team_linear_traits_generic(
+                    &traits::job_weights(workforce::Job::BUILDER),
+                    workforce::Job::BUILDER,
+                    team,
+                    population,
+                    TaggedExp{
+                        tag: "base produciton".to_string(),
+                        e: Exp::Constant{v: types::Millis::from_i32(1)},
+                    }),
+                cotenure_exp(workforce::Job::BUILDER, team, population.rapport_tracker()),
+                morale_exp(team, population),
+                harmony_exp(team, population),
+                leader_exp(team, population),
+            ],
         },
         tag: "production".to_string(),
     }
@@ -210,25 +301,53 @@ mod exp_tests {
         use super::TaggedExp;
         use super::Exp;
         use super::Op;
+        use crate::types::Millis;
 
         let e = TaggedExp{
             e: Exp::BinaryExp{
                 op: Op::MULTIPLY,
-                v1: Box::new(TaggedExp{e: Exp::Constant{v: 4.0}, tag: "base".to_string()}),
+                v1: Box::new(TaggedExp{e: Exp::Constant{v: Millis::from_f32(4.0)}, tag: "base".to_string()}),
                 v2: Box::new(TaggedExp{
                     e: Exp::ArrayExp{
                         op: Op::SUM,
                         vs: vec![
-                            TaggedExp{e: Exp::Constant{v: 1.1}, tag: "skill1".to_string()},
-                            TaggedExp{e: Exp::Constant{v: 1.2}, tag: "skill2".to_string()},
-                            TaggedExp{e: Exp::Constant{v: 1.3}, tag: "skill3".to_string()},
+                            TaggedExp{e: Exp::Constant{v: Millis::from_f32(1.1)}, tag: "skill1".to_string()},
+                            TaggedExp{e: Exp::Constant{v: Millis::from_f32(1.2)}, tag: "skill2".to_string()},
+                            TaggedExp{e: Exp::Constant{v: Millis::from_f32(1.3)}, tag: "skill3".to_string()},
                         ],
                     },
                     tag: "boost".to_string()}),
             },
             tag: "production".to_string(),
         };
-        assert_eq!(4.0 * (1.1 + 1.2 + 1.3), e.eval(), "Error evaluating: {}", e.stringify(""));
+        assert_eq!(Millis::from_f32(4.0 * (1.1 + 1.2 + 1.3)), e.eval(), "Error evaluating: {}", e.stringify(""));
+    }
+
+    #[test]
+    fn leader() {
+        use crate::character::{Character, CharacterId};
+        use crate::population::Population;
+        use crate::team::Team;
+        use crate::traits::Trait;
+        use crate::types::{self, Millis};
+
+        let mut rng = types::new_rng(0);
+        let mut population = Population::new(
+            (0..2).map(|i| Character::new_random(CharacterId(i), &mut rng)).collect());
+        for t in Trait::iter() {
+            *population.mut_character_with_id(CharacterId(0)).unwrap().mut_trait(t) = t.definition().average_rating();
+        }
+        let mut team = Team::new_with_ids(maplit::hashset!{CharacterId(0), CharacterId(1)});
+        assert_eq!(Millis::from_i32(1), super::leader_exp(&team, &population).eval());
+
+        // An average leader makes no difference, and only counts once.
+        team.set_leader(Some(CharacterId(0))).expect("set_leader");
+        assert_eq!(Millis::from_i32(1), super::leader_exp(&team, &population).eval());
+        assert_eq!(0.0, team.harmony(&population).leadership);
+
+        population.mut_character_with_id(CharacterId(0)).unwrap().mut_trait(Trait::LEADERSHIP).value += 20;
+        assert_eq!(Millis::from_f32(1.1), super::leader_exp(&team, &population).eval());
+        assert_eq!(0.0, team.harmony(&population).leadership);
     }
 }
 
@@ -0,0 +1,114 @@
+use super::character;
+use super::population;
+
+use rand::Rng;
+
+// Adults who've spent this many turns on the same team may set up a household
+// together, with HOUSEHOLD_PROBABILITY each turn.
+const HOUSEHOLD_RAPPORT_TURNS: i32 = 12;
+const HOUSEHOLD_PROBABILITY: f64 = 0.2;
+
+// A household has a child with BIRTH_PROBABILITY each turn, as long as both
+// parents are young enough and nobody is starving.
+const BIRTH_PROBABILITY: f64 = 0.05;
+const MAX_PARENT_AGE: i32 = 45;
+
+pub struct Consequences {
+    pub households: Vec<population::Household>,
+    pub births: Vec<character::Character>,
+}
+
+// Children are given ids starting from 'next_cid'.
+pub fn end_of_turn<R: Rng + ?Sized>(population: &population::Population,
+                                    next_cid: character::CharacterId,
+                                    starving: bool,
+                                    rng: &mut R) -> Consequences {
+    let mut consequences = Consequences{households: vec![], births: vec![]};
+
+    let mut single = population.characters().iter()
+        .filter(|c| c.is_adult() && population.household_of(c.id()).is_none())
+        .map(|c| c.id())
+        .collect::<Vec<character::CharacterId>>();
+    single.sort();
+    let mut paired = std::collections::HashSet::new();
+    for (i, &a) in single.iter().enumerate() {
+        for &b in &single[i + 1..] {
+            if paired.contains(&a) || paired.contains(&b) {
+                continue;
+            }
+            if population.rapport_tracker().turns_on_same_team(&a, &b) >= HOUSEHOLD_RAPPORT_TURNS &&
+                rng.gen_bool(HOUSEHOLD_PROBABILITY) {
+                consequences.households.push(population::Household::new(a, b));
+                paired.insert(a);
+                paired.insert(b);
+            }
+        }
+    }
+
+    if starving {
+        return consequences;
+    }
+    let mut next_cid = next_cid;
+    for household in population.households() {
+        let parents = match (population.character_with_id(household.a), population.character_with_id(household.b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => continue,
+        };
+        if parents.0.age() > MAX_PARENT_AGE || parents.1.age() > MAX_PARENT_AGE {
+            continue;
+        }
+        if rng.gen_bool(BIRTH_PROBABILITY) {
+            consequences.births.push(character::Character::new_child(next_cid, parents, rng));
+            next_cid = character::CharacterId(next_cid.0 + 1);
+        }
+    }
+    return consequences;
+}
+
+#[cfg(test)]
+mod family_tests {
+    use crate::character::{Character, CharacterId, Trait};
+    use crate::population::{Household, Population};
+    use crate::types;
+    use crate::workforce::Job;
+
+    #[test]
+    fn households_and_births() {
+        let mut rng = types::new_rng(3);
+        let mut population = Population::new(
+            (0..3).map(|i| Character::new_random(CharacterId(i), &mut rng)).collect());
+        for c in vec![CharacterId(0), CharacterId(1), CharacterId(2)] {
+            population.mut_character_with_id(c).unwrap().set_age(25);
+        }
+        for _ in 0..super::HOUSEHOLD_RAPPORT_TURNS {
+            population.mut_rapport_tracker().inc_turns_on_same_team(Job::FARMER, &CharacterId(2), &CharacterId(0));
+        }
+
+        let households = (0..50).map(|_| super::end_of_turn(&population, CharacterId(3), false, &mut rng))
+            .find(|c| !c.households.is_empty()).expect("a household should form").households;
+        assert_eq!(vec![Household{a: CharacterId(0), b: CharacterId(2)}], households);
+        population.add_household(households[0]).expect("add_household");
+        assert!(population.add_household(Household::new(CharacterId(1), CharacterId(2))).is_err());
+
+        // Nobody has children while starving.
+        for _ in 0..50 {
+            assert!(super::end_of_turn(&population, CharacterId(3), true, &mut rng).births.is_empty());
+        }
+        let births = (0..200).map(|_| super::end_of_turn(&population, CharacterId(3), false, &mut rng))
+            .find(|c| !c.births.is_empty()).expect("a child should be born").births;
+        assert_eq!(1, births.len());
+        let child = &births[0];
+        assert_eq!(CharacterId(3), child.id());
+        assert_eq!(0, child.age());
+        assert!(!child.is_adult());
+        for t in Trait::iter() {
+            let inherited = (population.character_with_id(CharacterId(0)).unwrap().get_trait_desc(t).capacity +
+                             population.character_with_id(CharacterId(2)).unwrap().get_trait_desc(t).capacity) / 2;
+            assert!((child.get_trait_desc(t).capacity - inherited).abs() <= 25);
+            assert!(child.get_trait_value(t) <= child.get_trait_desc(t).capacity);
+        }
+
+        population.remove(CharacterId(2));
+        assert!(population.households().is_empty());
+    }
+}
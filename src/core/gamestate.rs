@@ -1,19 +1,39 @@
+use super::aging;
 use super::castle;
 use super::character;
+use super::diff;
 use super::economy;
+use super::family;
+use super::logformat;
+use super::logstore;
+use super::migrations;
+use super::morale;
 use super::population;
+use super::starvation;
 use super::statemachine;
 use super::types;
 use super::workforce;
 
 use log::{info};
-use anyhow::Context;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+// How often the save log is compacted down to a single checkpoint, which only
+// happens at the end of a turn.
+const CHECKPOINT_EVERY_N_DELTAS: usize = 250;
+const CHECKPOINT_EVERY_N_BYTES: usize = 1024 * 1024;
+// verify_save can only narrow a divergence down to the mutations between two
+// logged states, so by default a snapshot is logged every so often.
+const SNAPSHOT_EVERY_N_DELTAS: usize = 20;
+
+// Warn the player when the food will run out within this many turns.
+const FOOD_RUNNING_LOW_TURNS: i32 = 3;
+
 pub struct GameSpec {
     pub initial_potential_characters: usize,
     pub initial_characters: usize,
+    pub seed: u64,
+    pub save_format: logformat::LogFormat,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -26,6 +46,11 @@ pub struct GameStateT {
     pub castle: castle::Castle,
 
     pub next_valid_cid: character::CharacterId,
+
+    pub rng: types::GameRng,
+
+    // How many turns in a row there hasn't been enough food.
+    pub turns_starving: i32,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -34,27 +59,53 @@ pub enum UserCommand {
 //    Unassign{cid: character::CharacterId},
     AddCharacter{character: character::Character},
     AddToBuildQueue{infra: castle::Infrastructure},
+    // None removes the team's leader.
+    SetTeamLeader{job: workforce::Job, cid: Option<character::CharacterId>},
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-enum MutationT {
-    EndTurn{builder_accumulation: types::Millis, food: types::Millis},
+// Everything that changes a GameStateT. These are what the save log is made
+// of; see GameState::inspect_save.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum MutationT {
+    EndTurn{builder_accumulation: types::Millis, food: types::Millis, rng: types::GameRng, turns_starving: i32},
     UserCommand{cmd: UserCommand},
     UpdateCharacter{character_delta: character::CharacterDelta},
     CompleteInfrastructure{infra: castle::Infrastructure},
+    RemoveCharacter{cid: character::CharacterId, reason: DepartureReason},
+    FormHousehold{household: population::Household},
+    // Children join the population at birth, but only join the workforce
+    // when they ComeOfAge.
+    Birth{character: character::Character},
+    ComeOfAge{cid: character::CharacterId},
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum DepartureReason {
+    Starved,
+    LeftHungry,
+    OldAge,
+    LeftUnhappy,
 }
 
 fn apply_mutation(state: &mut GameStateT, m: &MutationT) -> anyhow::Result<()> {
     match &m {
-        &MutationT::EndTurn{builder_accumulation, food} => {
+        &MutationT::EndTurn{builder_accumulation, food, rng, turns_starving} => {
             state.turn = state.turn + 1;
 
             state.workforce.advance_turn();
-            for (c1, c2) in state.workforce.farmers().member_pairs() {
-                state.population.mut_rapport_tracker().inc_turns_on_same_team(&c1, &c2);
+            let pairs = state.workforce.teams()
+                .map(|(job, team)| (*job, team.member_pairs()))
+                .collect::<Vec<_>>();
+            state.population.mut_rapport_tracker().work_turn(&pairs);
+            let ids = state.population.characters().iter().map(|c| c.id()).collect::<Vec<character::CharacterId>>();
+            for cid in ids {
+                let job = state.workforce.job_of(cid);
+                state.population.mut_character_with_id(cid).expect("character").work_turn(job);
             }
             state.castle.build_queue.progress = *builder_accumulation;
             state.food = *food;
+            state.rng = rng.clone();
+            state.turns_starving = *turns_starving;
         }
         &MutationT::UserCommand{cmd} => apply_user_command(state, cmd)?,
         &MutationT::UpdateCharacter{character_delta} => {
@@ -63,6 +114,18 @@ fn apply_mutation(state: &mut GameStateT, m: &MutationT) -> anyhow::Result<()> {
             for (&t, &new_v) in &character_delta.changed_trait_values {
                 character.mut_trait(t).value = new_v;
             }
+            for (&t, &new_capacity) in &character_delta.changed_trait_capacities {
+                character.mut_trait(t).capacity = new_capacity;
+            }
+            if let Some(health) = character_delta.health {
+                character.set_health(health);
+            }
+            if let Some(morale) = character_delta.morale {
+                character.set_morale(morale);
+            }
+            if let Some(age) = character_delta.age {
+                character.set_age(age);
+            }
         },
         &MutationT::CompleteInfrastructure{infra} => {
             match infra {
@@ -70,7 +133,20 @@ fn apply_mutation(state: &mut GameStateT, m: &MutationT) -> anyhow::Result<()> {
                     state.castle.food_infrastructure.acres_of_farmland = state.castle.food_infrastructure.acres_of_farmland + 1;
                 }
             }
-        }
+        },
+        &MutationT::RemoveCharacter{cid, ..} => {
+            state.population.remove(*cid)
+                .ok_or_else(|| anyhow::anyhow!("no character with id {}", cid))?;
+            state.workforce.remove(*cid);
+        },
+        &MutationT::FormHousehold{household} => state.population.add_household(*household)?,
+        &MutationT::Birth{character} => {
+            if character.id().0 >= state.next_valid_cid.0 {
+                state.next_valid_cid = character::CharacterId(character.id().0 + 1);
+            }
+            state.population.add(character.clone());
+        },
+        &MutationT::ComeOfAge{cid} => state.workforce.add_unassigned(*cid),
     }
 
     return Ok(());
@@ -89,30 +165,60 @@ fn apply_user_command(state: &mut GameStateT, c: &UserCommand) -> anyhow::Result
         },
         &UserCommand::AddToBuildQueue{infra} => {
             state.castle.build_queue.queue.push(*infra);
-        }
+        },
+        &UserCommand::SetTeamLeader{job, cid} => state.workforce.set_leader(*job, *cid)?,
     }
 
     return Ok(());
 }
 
+#[derive(Debug)]
 pub enum Prompt {
     AsylumSeeker(character::Character),
+    // There wasn't enough food this turn, for the 'turns_starving'th turn in
+    // a row.
+    FoodShortage{turns_starving: i32},
+    FoodRunningLow{turns_left: i32},
+    CharacterDied(character::Character, DepartureReason),
+    CharacterLeft(character::Character, DepartureReason),
+    HouseholdFormed(character::Character, character::Character),
+    Born(character::Character),
+    CameOfAge(character::Character),
+    // The castle's average morale is below morale::UNREST_MORALE.
+    Unrest{average_morale: i32},
+}
+
+fn new_saver(store: Box<dyn logstore::LogStore>, format: logformat::LogFormat) -> statemachine::Saver<GameStateT, MutationT> {
+    let mut saver = statemachine::Saver::new(store, migrations::SCHEMA_VERSION);
+    saver.set_format(format);
+    return saver;
 }
 
 pub struct GameState {
     machine: statemachine::PersistentStateMachine<GameStateT, MutationT>,
+
+    // Commands taken back with undo() this turn, most recent last.
+    redo_stack: Vec<UserCommand>,
 }
 
 impl GameState {
-    pub fn init(spec: GameSpec,
+    pub fn init<P: AsRef<std::path::Path>>(spec: GameSpec,
                 initial_characters: Vec<character::Character>,
-                save_file: std::fs::File) -> anyhow::Result<GameState> {
+                rng: types::GameRng,
+                save_path: P) -> anyhow::Result<GameState> {
+        return GameState::init_with_store(
+            spec, initial_characters, rng, Box::new(logstore::FileLogStore::new(save_path)));
+    }
+
+    pub fn init_with_store(spec: GameSpec,
+                           initial_characters: Vec<character::Character>,
+                           rng: types::GameRng,
+                           store: Box<dyn logstore::LogStore>) -> anyhow::Result<GameState> {
         assert_eq!(initial_characters.len(), spec.initial_characters as usize,
                    "Please pick {} initial characters ({} selected)",
                    spec.initial_characters, initial_characters.len());
 
-        return Ok(GameState{
-            machine: statemachine::PersistentStateMachine::init(
+        return GameState::from_machine(statemachine::PersistentStateMachine::init(
                 GameStateT{
                     turn: 0,
                     food: types::Millis::from_i32(2 * spec.initial_characters as i32),
@@ -123,53 +229,321 @@ impl GameState {
                         |so_far, candidate| character::CharacterId(std::cmp::max(so_far.0, candidate.id().0 + 1))),
                     population: population::Population::new(initial_characters),
                     castle: castle::Castle::init(&spec),
+                    rng: rng,
+                    turns_starving: 0,
                 },
                 Box::new(apply_mutation),
-                statemachine::Saver::new(std::rc::Rc::new(std::sync::Mutex::new(save_file))),
-            )?,
-        });
+                new_saver(store, spec.save_format),
+            )?);
     }
 
-    fn restore_helper<P: AsRef<std::path::Path> + std::fmt::Debug>(filename: &P) -> anyhow::Result<GameStateT> {
-        use std::io::BufRead;
+    fn from_machine(mut machine: statemachine::PersistentStateMachine<GameStateT, MutationT>) -> anyhow::Result<GameState> {
+        machine.set_checkpoint_policy(statemachine::CheckpointPolicy{
+            max_deltas: Some(CHECKPOINT_EVERY_N_DELTAS),
+            max_bytes: Some(CHECKPOINT_EVERY_N_BYTES),
+        });
+        machine.set_durability(statemachine::Durability::SyncOnCommit);
+        machine.set_snapshot_interval(Some(SNAPSHOT_EVERY_N_DELTAS));
+        return Ok(GameState{machine: machine, redo_stack: vec![]});
+    }
 
-        let restore_file = std::fs::File::open(filename)
-            .with_context(|| format!("Opening {:?} for restore", *filename))?;
-        let restore_reader = std::io::BufReader::new(restore_file);
-        return Ok(statemachine::PersistentStateMachine::recover(
-            &mut restore_reader.lines().map(|r| r.expect("error reading line")),
-            &apply_mutation)?);
+    // Returns the recovered state, and what recovery found in the log.
+    fn restore_helper(store: &mut dyn logstore::LogStore,
+                      stop_at: statemachine::StopAt<GameStateT, MutationT>,
+                      include_history: bool) -> anyhow::Result<(GameStateT, statemachine::RecoveryReport)> {
+        let (state, report) = statemachine::PersistentStateMachine::recover_until(
+            store,
+            &apply_mutation,
+            &migrations::schema(),
+            stop_at,
+            include_history)?;
+        info!("Recovered turn {} from {} {:?} log entries ({} torn entries dropped)",
+              state.turn, report.entries_read, report.format, report.torn_entries_dropped);
+        return Ok((state, report));
     }
 
     pub fn restore<P: AsRef<std::path::Path> + std::fmt::Debug>(filename: P) -> anyhow::Result<GameState> {
-        let state = GameState::restore_helper(&filename)?;
-        let save_file = std::fs::OpenOptions::new().write(true).append(true).open(&filename)
-            .with_context(|| format!("Opening {:?} for as save_file", &filename))?;
+        return GameState::restore_from_store(Box::new(logstore::FileLogStore::new(filename)));
+    }
 
-        return Ok(GameState{
-            machine: statemachine::PersistentStateMachine::init(
-                state,
-                Box::new(apply_mutation),
-                statemachine::Saver::new(std::rc::Rc::new(std::sync::Mutex::new(save_file))))?,
-        });
+    pub fn restore_from_store(store: Box<dyn logstore::LogStore>) -> anyhow::Result<GameState> {
+        // The game keeps saving in whatever format it was created with.
+        let (machine, report) = statemachine::PersistentStateMachine::resume(
+            Box::new(apply_mutation),
+            statemachine::Saver::new(store, migrations::SCHEMA_VERSION),
+            &migrations::schema())?;
+        info!("Recovered turn {} from {} {:?} log entries ({} torn entries dropped)",
+              machine.state().turn, report.entries_read, report.format, report.torn_entries_dropped);
+        return GameState::from_machine(machine);
+    }
+
+    // Restores the game as it was at the end of 'turn' (i.e. including any
+    // commands issued during that turn), replaying the save's history if
+    // 'turn' has since been compacted away. The save file is left untouched
+    // and nothing done to the returned game is saved, unless it is fork()ed.
+    pub fn restore_at_turn<P: AsRef<std::path::Path> + std::fmt::Debug>(filename: P, turn: i32) -> anyhow::Result<GameState> {
+        // Only user commands are applied between turns, so anything else
+        // means the game has started advancing past 'turn'.
+        let past_turn = |state: &GameStateT, m: &MutationT| {
+            return state.turn >= turn && match m {
+                MutationT::UserCommand{..} => false,
+                _ => true,
+            };
+        };
+        let (state, report) = GameState::restore_helper(
+            &mut logstore::FileLogStore::new(&filename), statemachine::StopAt::Before(&past_turn), true)?;
+        if state.turn < turn {
+            return Err(anyhow::anyhow!(
+                "Can't restore {:?} to turn {}: the log only goes up to turn {}", filename, turn, state.turn));
+        }
+        if state.turn > turn {
+            // e.g. because the save was forked from another one at that turn.
+            return Err(anyhow::anyhow!(
+                "Can't restore {:?} to turn {}: the log starts at turn {}", filename, turn, state.turn));
+        }
+
+        return GameState::from_machine(statemachine::PersistentStateMachine::init(
+            state,
+            Box::new(apply_mutation),
+            new_saver(Box::new(logstore::MemoryLogStore::new()), report.format))?);
+    }
+
+    // Starts a new save file, in the same format, from the current state. The
+    // returned game saves to 'filename'; this one keeps saving wherever it did
+    // before.
+    pub fn fork<P: AsRef<std::path::Path>>(&self, filename: P) -> anyhow::Result<GameState> {
+        return GameState::from_machine(statemachine::PersistentStateMachine::init(
+            self.machine.state().clone(),
+            Box::new(apply_mutation),
+            new_saver(Box::new(logstore::FileLogStore::new(filename)), self.machine.format()))?);
+    }
+
+    // Calls 'visit' for each entry in the save file 'filename', with the state
+    // just before that entry was applied. The file is left untouched.
+    pub fn inspect_save<P: AsRef<std::path::Path>>(
+        filename: P,
+        visit: &mut dyn FnMut(usize, &statemachine::LogEntry<GameStateT, MutationT>, Option<&GameStateT>))
+        -> anyhow::Result<statemachine::RecoveryReport> {
+        return statemachine::PersistentStateMachine::inspect(
+            &mut logstore::FileLogStore::new(filename),
+            &apply_mutation,
+            &migrations::schema(),
+            visit);
+    }
+
+    // What changed in the save file 'filename' between the ends of turns
+    // 'from' and 'to'. Both turns must be in the log or its history; see
+    // restore_at_turn.
+    pub fn diff_turns<P: AsRef<std::path::Path> + std::fmt::Debug>(
+        filename: P, from: i32, to: i32) -> anyhow::Result<diff::GameStateDiff> {
+        let before = GameState::restore_at_turn(&filename, from)?;
+        let after = GameState::restore_at_turn(&filename, to)?;
+        return Ok(diff::diff(before.state(), after.state()));
+    }
+
+    // Checks that replaying the save file 'filename' reproduces the snapshots
+    // and checkpoints in it, i.e. that everything that changes the game is
+    // captured by the logged mutations. A divergence is only narrowed down to
+    // the mutations since the last state that matched; see
+    // set_snapshot_interval.
+    pub fn verify_save<P: AsRef<std::path::Path>>(filename: P) -> anyhow::Result<statemachine::Verification> {
+        return statemachine::PersistentStateMachine::<GameStateT, MutationT>::verify(
+            &mut logstore::FileLogStore::new(filename),
+            &apply_mutation,
+            &migrations::schema());
+    }
+
+    // Writes a copy of the save file 'from' to 'to' in 'format', e.g. to get a
+    // readable JSON version of a CBOR save. Returns the number of log entries
+    // written.
+    pub fn convert_save<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+        from: P, to: Q, format: logformat::LogFormat) -> anyhow::Result<usize> {
+        return statemachine::PersistentStateMachine::<GameStateT, MutationT>::convert(
+            &mut logstore::FileLogStore::new(from),
+            &mut logstore::FileLogStore::new(to),
+            &migrations::schema(),
+            format);
+    }
+
+    // Writes the save log from a background thread, so that e.g. advance_turn
+    // doesn't wait on the disk. Commits still wait for a sync under
+    // Durability::SyncOnCommit, so pair this with a weaker durability to
+    // avoid waiting at all, and call flush() or close() to make sure
+    // everything has been saved.
+    pub fn save_in_background(&mut self) {
+        self.machine.write_in_background();
+    }
+
+    // Waits until everything done so far is saved, and reports any error
+    // saving it.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        return self.machine.flush();
+    }
+
+    // As flush(), for a game that won't be played any further.
+    pub fn close(mut self) -> anyhow::Result<()> {
+        return self.machine.close();
+    }
+
+    // Logs a snapshot of the game state every 'every_n_deltas' mutations, for
+    // verify_save to check replay against, or never if None. Logging them
+    // more often pins down a divergence more closely, at the cost of bigger
+    // saves. Defaults to SNAPSHOT_EVERY_N_DELTAS.
+    pub fn set_snapshot_interval(&mut self, every_n_deltas: Option<usize>) {
+        self.machine.set_snapshot_interval(every_n_deltas);
+    }
+
+    // Lets e.g. a UI react to every mutation as it happens. Observers belong
+    // to this GameState, and aren't carried over by fork().
+    pub fn add_observer(&mut self, observer: statemachine::Observer<GameStateT, MutationT>) {
+        self.machine.add_observer(observer);
+    }
+
+    // Defaults to SyncOnCommit, which syncs the save log at the end of every
+    // turn. Simulations that don't care about crashes can turn that off.
+    pub fn set_durability(&mut self, durability: statemachine::Durability) {
+        self.machine.set_durability(durability);
     }
 
     pub fn execute_command(&mut self, command: &UserCommand) -> anyhow::Result<()> {
+        self.redo_stack.clear();
         return self.machine.apply(&MutationT::UserCommand{cmd: command.clone()});
     }
 
+    // Takes back the most recent command issued this turn, returning it.
+    pub fn undo(&mut self) -> anyhow::Result<UserCommand> {
+        // Only user commands are applied between turns, so if the latest
+        // mutation is a command it must belong to the current turn.
+        match self.machine.last_delta() {
+            Some(MutationT::UserCommand{..}) => {},
+            _ => return Err(anyhow::anyhow!("Nothing to undo this turn")),
+        }
+
+        match self.machine.revert_last_delta()? {
+            MutationT::UserCommand{cmd} => {
+                self.redo_stack.push(cmd.clone());
+                return Ok(cmd);
+            },
+            _ => unreachable!("last_delta was a UserCommand"),
+        }
+    }
+
+    // Re-issues the most recently undone command, returning it.
+    pub fn redo(&mut self) -> anyhow::Result<UserCommand> {
+        let cmd = self.redo_stack.pop().ok_or(anyhow::Error::msg("Nothing to redo"))?;
+        self.machine.apply(&MutationT::UserCommand{cmd: cmd.clone()})?;
+        return Ok(cmd);
+    }
+
     // TODO(mrjones): Make GameState immutable, and make this return a copy?
     pub fn advance_turn(&mut self) -> anyhow::Result<Vec<Prompt>> {
-        // TODO(mrjones): Starvation
+        self.redo_stack.clear();
+
+        // Food can't go below zero: whatever couldn't be eaten is a shortfall,
+        // which the population suffers for below.
+        let consumed = self.food_economy().consumed_per_turn;
+        let food_delta = self.food_delta();
+        let available = self.machine.state().food + food_delta;
+        let shortfall = std::cmp::max(types::Millis::zero(), types::Millis::zero() - available);
         let food = std::cmp::min(
             self.machine.state().castle.food_infrastructure.food_storage,
-            self.machine.state().food + self.food_delta());
+            std::cmp::max(types::Millis::zero(), available));
+        let turns_starving = if shortfall > types::Millis::zero() {
+            self.machine.state().turns_starving + 1
+        } else {
+            0
+        };
 
+        // Draws come from a copy of the state's rng, which is written back by
+        // the EndTurn mutation below.
+        let mut rng = self.machine.state().rng.clone();
 
+        for char_delta in self.machine.state().population.compute_end_of_turn_deltas(&mut rng) {
+            self.machine.apply(&MutationT::UpdateCharacter{character_delta: char_delta})?;
+        }
 
-        for char_delta in self.machine.state().population.compute_end_of_turn_deltas() {
+        let mut prompts = vec![];
+        let mut losses = 0;
+        let consequences = starvation::end_of_turn(
+            &self.machine.state().population, shortfall, consumed, turns_starving, &mut rng);
+        for char_delta in consequences.character_deltas {
             self.machine.apply(&MutationT::UpdateCharacter{character_delta: char_delta})?;
         }
+        for cid in consequences.died {
+            let reason = DepartureReason::Starved;
+            prompts.push(Prompt::CharacterDied(self.remove_character(cid, reason)?, reason));
+            losses += 1;
+        }
+        if let Some(cid) = consequences.left {
+            let reason = DepartureReason::LeftHungry;
+            prompts.push(Prompt::CharacterLeft(self.remove_character(cid, reason)?, reason));
+            losses += 1;
+        }
+        if aging::is_end_of_year(self.machine.state().turn) {
+            let consequences = aging::end_of_year(&self.machine.state().population, &mut rng);
+            for char_delta in consequences.character_deltas {
+                self.machine.apply(&MutationT::UpdateCharacter{character_delta: char_delta})?;
+            }
+            for cid in consequences.died {
+                let reason = DepartureReason::OldAge;
+                prompts.push(Prompt::CharacterDied(self.remove_character(cid, reason)?, reason));
+                losses += 1;
+            }
+            let coming_of_age = self.machine.state().population.characters().iter()
+                .filter(|c| c.is_adult() && !self.machine.state().workforce.contains(c.id()))
+                .cloned()
+                .collect::<Vec<character::Character>>();
+            for c in coming_of_age {
+                self.machine.apply(&MutationT::ComeOfAge{cid: c.id()})?;
+                prompts.push(Prompt::CameOfAge(c));
+            }
+        }
+
+        let consequences = family::end_of_turn(
+            &self.machine.state().population, self.machine.state().next_valid_cid, turns_starving > 0, &mut rng);
+        for household in consequences.households {
+            self.machine.apply(&MutationT::FormHousehold{household: household})?;
+            let population = &self.machine.state().population;
+            match (population.character_with_id(household.a), population.character_with_id(household.b)) {
+                (Some(a), Some(b)) => prompts.push(Prompt::HouseholdFormed(a.clone(), b.clone())),
+                _ => {},
+            }
+        }
+        let births = consequences.births.len();
+        for child in consequences.births {
+            self.machine.apply(&MutationT::Birth{character: child.clone()})?;
+            prompts.push(Prompt::Born(child));
+        }
+
+        let circumstances = morale::Circumstances{
+            food_surplus: food_delta >= types::Millis::zero(),
+            starving: turns_starving > 0,
+            losses: losses,
+            births: births,
+        };
+        let consequences = morale::end_of_turn(
+            &self.machine.state().population, &self.machine.state().workforce, &circumstances, &mut rng);
+        for char_delta in consequences.character_deltas {
+            self.machine.apply(&MutationT::UpdateCharacter{character_delta: char_delta})?;
+        }
+        for cid in consequences.left {
+            let reason = DepartureReason::LeftUnhappy;
+            prompts.push(Prompt::CharacterLeft(self.remove_character(cid, reason)?, reason));
+        }
+        if let Some(average_morale) = morale::average_morale(&self.machine.state().population) {
+            if average_morale < morale::UNREST_MORALE {
+                prompts.push(Prompt::Unrest{average_morale: average_morale});
+            }
+        }
+
+        if turns_starving > 0 {
+            prompts.push(Prompt::FoodShortage{turns_starving: turns_starving});
+        } else if food_delta < types::Millis::zero() {
+            let turns_left = (food.to_f32() / (types::Millis::zero() - food_delta).to_f32()) as i32;
+            if turns_left < FOOD_RUNNING_LOW_TURNS {
+                prompts.push(Prompt::FoodRunningLow{turns_left: turns_left});
+            }
+        }
 
         let builder_production = self.builder_economy().production.eval();
         let build_queue_state = self.machine.state().castle.build_queue.turn_end(builder_production);
@@ -179,21 +553,32 @@ impl GameState {
             info!("Completed infrastructure: {:?}", infra);
         }
 
+        if rng.gen_bool(0.1) {
+            prompts.push(Prompt::AsylumSeeker(character::Character::new_random(
+                self.machine.state().next_valid_cid, &mut rng)));
+        }
+
         // TODO: Need to decide what explicitly gets written down, and what gets
         // recomputed by the execute_mutation framework...
         self.machine.apply(&MutationT::EndTurn{
             food: food,
             builder_accumulation: build_queue_state.progress,
+            rng: rng,
+            turns_starving: turns_starving,
         })?;
+        self.machine.commit()?;
 
-        let mut prompts = vec![];
-        if rand::thread_rng().gen_bool(0.1) {
-            prompts.push(Prompt::AsylumSeeker(character::Character::new_random(
-                self.machine.state().next_valid_cid)));
-        }
         return Ok(prompts);
     }
 
+    // Returns the character as they were when they left.
+    fn remove_character(&mut self, cid: character::CharacterId, reason: DepartureReason) -> anyhow::Result<character::Character> {
+        let character = self.machine.state().population.character_with_id(cid)
+            .ok_or_else(|| anyhow::anyhow!("no character with id {}", cid))?.clone();
+        self.machine.apply(&MutationT::RemoveCharacter{cid: cid, reason: reason})?;
+        return Ok(character);
+    }
+
     pub fn food_economy(&self) -> economy::FoodEconomy {
         return economy::food(self.machine.state().workforce.farmers(), &self.machine.state().castle.food_infrastructure, &self.machine.state().population);
     }
@@ -219,6 +604,10 @@ impl GameState {
         return &self.machine.state().castle;
     }
 
+    pub fn state(&self) -> &GameStateT {
+        return self.machine.state();
+    }
+
     pub fn turn(&self) -> i32 {
         return self.machine.state().turn;
     }
@@ -228,3 +617,416 @@ impl GameState {
     }
 
 }
+
+#[cfg(test)]
+mod gamestate_tests {
+    use super::GameSpec;
+    use crate::character::{Character, CharacterId};
+    use crate::initialsetup::InitialSetup;
+    use crate::logformat::LogFormat;
+    use crate::logstore::FileLogStore;
+
+    // A save in the temp directory, deleted along with its history when this
+    // goes out of scope.
+    struct TempSave {
+        path: std::path::PathBuf,
+    }
+
+    impl TempSave {
+        fn new(name: &str) -> TempSave {
+            return TempSave{path: std::env::temp_dir().join(format!("gamestate_test_{}.{}", name, std::process::id()))};
+        }
+    }
+
+    impl Drop for TempSave {
+        fn drop(&mut self) {
+            for history in FileLogStore::history_paths(&self.path) {
+                let _ = std::fs::remove_file(history);
+            }
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    // Begins the game in 'spec', saved to 'save', with the first
+    // 'spec.initial_characters' candidates after 'customize' has had a go at
+    // them. Returns the game and the ids of its characters.
+    fn begin(save: &TempSave, spec: GameSpec, customize: &dyn Fn(&mut Character)) -> (super::GameState, Vec<CharacterId>) {
+        let selected = spec.initial_characters;
+        let mut setup = InitialSetup::new(spec);
+        for c in setup.character_candidates.iter_mut() {
+            customize(c);
+        }
+        let ids = setup.character_candidates.iter().take(selected).map(|c| c.id()).collect::<Vec<_>>();
+        let game = setup.begin(ids.iter().cloned().collect(), &save.path).expect("begin");
+        return (game, ids);
+    }
+
+    // As begin(), for a JSON save of 'characters' picked out of 'candidates'
+    // as they come.
+    fn new_game(save: &TempSave, seed: u64, candidates: usize, characters: usize) -> (super::GameState, Vec<CharacterId>) {
+        return begin(save, GameSpec{
+            initial_potential_characters: candidates,
+            initial_characters: characters,
+            seed: seed,
+            save_format: LogFormat::Json,
+        }, &|_| {});
+    }
+
+    fn play(seed: u64, format: LogFormat, save: &TempSave, restore_midway: bool) -> Vec<String> {
+        let (mut game, _) = begin(save, GameSpec{
+            initial_potential_characters: 6,
+            initial_characters: 3,
+            seed: seed,
+            save_format: format,
+        }, &|_| {});
+
+        let mut transcript = vec![];
+        for turn in 0..20 {
+            if restore_midway && turn == 10 {
+                game = super::GameState::restore(&save.path).expect("restore");
+            }
+            for prompt in game.advance_turn().expect("advance_turn") {
+                match prompt {
+                    super::Prompt::AsylumSeeker(c) => transcript.push(format!("seeker: {}", c.full_debug_string())),
+                    super::Prompt::CharacterDied(c, reason) => transcript.push(format!("died ({:?}): {}", reason, c.full_debug_string())),
+                    super::Prompt::CharacterLeft(c, reason) => transcript.push(format!("left ({:?}): {}", reason, c.full_debug_string())),
+                    other => transcript.push(format!("{:?}", other)),
+                }
+            }
+            transcript.push(format!("food: {}", game.food()));
+        }
+        for c in game.population().characters() {
+            transcript.push(c.full_debug_string());
+        }
+        return transcript;
+    }
+
+    #[test]
+    fn same_seed_same_game() {
+        let save_a = TempSave::new("seed_a");
+        let save_b = TempSave::new("seed_b");
+
+        assert_eq!(play(42, LogFormat::Json, &save_a, false), play(42, LogFormat::Json, &save_b, false));
+        assert_ne!(play(42, LogFormat::Json, &save_a, false), play(43, LogFormat::Json, &save_b, false));
+    }
+
+    #[test]
+    fn restore_continues_same_game() {
+        let save_a = TempSave::new("restore_a");
+        let save_b = TempSave::new("restore_b");
+
+        assert_eq!(play(7, LogFormat::Json, &save_a, false), play(7, LogFormat::Json, &save_b, true));
+    }
+
+    #[test]
+    fn parallel_games() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<super::GameState>();
+
+        let seeds = vec![1, 2, 3, 4];
+        let threads = seeds.iter().map(|&seed| std::thread::spawn(move || {
+            return play(seed, LogFormat::Json, &TempSave::new(&format!("parallel_{}", seed)), true);
+        })).collect::<Vec<_>>();
+        let parallel = threads.into_iter().map(|t| t.join().expect("join")).collect::<Vec<_>>();
+
+        for (&seed, transcript) in seeds.iter().zip(parallel) {
+            assert_eq!(play(seed, LogFormat::Json, &TempSave::new(&format!("sequential_{}", seed)), false), transcript);
+        }
+    }
+
+    #[test]
+    fn background_save() {
+        let save = TempSave::new("background");
+        let (mut game, _) = new_game(&save, 8, 4, 3);
+        game.save_in_background();
+        game.set_durability(crate::statemachine::Durability::None);
+        for _ in 0..10 {
+            game.advance_turn().expect("advance_turn");
+        }
+        let food = game.food();
+        game.close().expect("close");
+
+        let restored = super::GameState::restore(&save.path).expect("restore");
+        assert_eq!(10, restored.turn());
+        assert_eq!(food, restored.food());
+    }
+
+    #[test]
+    fn cbor_save() {
+        let json = TempSave::new("cbor_json");
+        let cbor = TempSave::new("cbor_cbor");
+        let converted = TempSave::new("cbor_converted");
+
+        // Restoring midway has to pick up the format, and keep saving in it.
+        assert_eq!(play(9, LogFormat::Json, &json, false), play(9, LogFormat::Cbor, &cbor, true));
+        assert_eq!(LogFormat::Cbor, LogFormat::detect(&std::fs::read(&cbor.path).unwrap()));
+        assert!(std::fs::metadata(&cbor.path).unwrap().len() < std::fs::metadata(&json.path).unwrap().len());
+
+        super::GameState::convert_save(&cbor.path, &converted.path, LogFormat::Json).expect("convert");
+        let converted_game = super::GameState::restore(&converted.path).expect("restore");
+        let original = super::GameState::restore(&json.path).expect("restore");
+        assert_eq!(original.turn(), converted_game.turn());
+        assert_eq!(original.food(), converted_game.food());
+        assert_eq!(LogFormat::Json, LogFormat::detect(&std::fs::read(&converted.path).unwrap()));
+    }
+
+    #[test]
+    fn replay_matches_snapshots() {
+        let save = TempSave::new("snapshots");
+        let (mut game, _) = new_game(&save, 21, 4, 3);
+        for _ in 0..20 {
+            for prompt in game.advance_turn().expect("advance_turn") {
+                match prompt {
+                    super::Prompt::AsylumSeeker(c) => game.execute_command(
+                        &super::UserCommand::AddCharacter{character: c}).expect("execute_command"),
+                    _ => {},
+                }
+            }
+        }
+
+        let verification = super::GameState::verify_save(&save.path).expect("verify_save");
+        // Snapshots are logged by default.
+        assert!(verification.states_checked > 0);
+        assert!(verification.divergence.is_none(), "{}", verification.divergence.unwrap());
+    }
+
+    #[test]
+    fn undo_redo() {
+        use super::UserCommand;
+        use crate::workforce::Job;
+
+        let save = TempSave::new("undo");
+        let (mut game, ids) = new_game(&save, 1, 3, 3);
+
+        game.execute_command(&UserCommand::AssignToTeam{cid: ids[0], job: Job::FARMER}).expect("assign");
+        game.advance_turn().expect("advance_turn");
+        game.execute_command(&UserCommand::AssignToTeam{cid: ids[1], job: Job::FARMER}).expect("assign");
+        game.execute_command(&UserCommand::AssignToTeam{cid: ids[2], job: Job::BUILDER}).expect("assign");
+
+        game.undo().expect("undo builder");
+        game.undo().expect("undo farmer");
+        assert!(game.undo().is_err(), "can't undo into the previous turn");
+        assert_eq!(1, game.workforce().farmers().members().len());
+        assert_eq!(0, game.workforce().builders().members().len());
+
+        game.redo().expect("redo farmer");
+        assert!(game.workforce().farmers().contains(&ids[1]));
+
+        assert!(game.execute_command(&UserCommand::SetTeamLeader{job: Job::FARMER, cid: Some(ids[2])}).is_err());
+        game.execute_command(&UserCommand::SetTeamLeader{job: Job::FARMER, cid: Some(ids[0])}).expect("lead");
+        game.execute_command(&UserCommand::SetTeamLeader{job: Job::FARMER, cid: Some(ids[1])}).expect("lead");
+        game.undo().expect("undo leader");
+        assert_eq!(Some(ids[0]), game.workforce().farmers().leader());
+
+        let restored = super::GameState::restore(&save.path).expect("restore");
+        assert_eq!(2, restored.workforce().farmers().members().len());
+        assert_eq!(0, restored.workforce().builders().members().len());
+        assert_eq!(Some(ids[0]), restored.workforce().farmers().leader());
+    }
+
+    #[test]
+    fn undo_across_checkpoints() {
+        use super::UserCommand;
+        use crate::workforce::Job;
+
+        let save = TempSave::new("undo_checkpoint");
+        let (mut game, ids) = new_game(&save, 1, 3, 3);
+        game.machine.set_checkpoint_policy(crate::statemachine::CheckpointPolicy::every_n_deltas(1));
+
+        // A checkpoint is due after every command, but waits for the turn to end.
+        game.advance_turn().expect("advance_turn");
+        game.execute_command(&UserCommand::AssignToTeam{cid: ids[0], job: Job::FARMER}).expect("assign");
+        game.execute_command(&UserCommand::AssignToTeam{cid: ids[1], job: Job::FARMER}).expect("assign");
+        game.undo().expect("undo farmer");
+        game.undo().expect("undo farmer");
+        assert!(game.undo().is_err(), "can't undo into the previous turn");
+        game.redo().expect("redo farmer");
+        game.redo().expect("redo farmer");
+
+        // Nor does reopening the save lose the turn's commands.
+        game.machine.close().expect("close");
+        let mut restored = super::GameState::restore(&save.path).expect("restore");
+        restored.undo().expect("undo after restore");
+        assert_eq!(1, restored.workforce().farmers().members().len());
+        assert!(restored.workforce().farmers().contains(&ids[0]));
+        restored.undo().expect("undo after restore");
+        assert!(restored.undo().is_err(), "can't undo into the previous turn");
+    }
+
+    #[test]
+    fn restore_at_turn_and_fork() {
+        let save = TempSave::new("time_travel");
+        let fork = TempSave::new("time_travel_fork");
+        let (mut game, _) = new_game(&save, 3, 3, 3);
+
+        let mut food_by_turn = vec![game.food()];
+        for _ in 0..10 {
+            game.advance_turn().expect("advance_turn");
+            food_by_turn.push(game.food());
+        }
+
+        let mut past = super::GameState::restore_at_turn(&save.path, 4).expect("restore_at_turn");
+        assert_eq!(4, past.turn());
+        assert_eq!(food_by_turn[4], past.food());
+        assert!(super::GameState::restore_at_turn(&save.path, 11).is_err());
+
+        past.advance_turn().expect("advance_turn");
+        let mut forked = past.fork(&fork.path).expect("fork");
+        forked.advance_turn().expect("advance_turn");
+        assert_eq!(6, super::GameState::restore(&fork.path).expect("restore fork").turn());
+        assert_eq!(10, super::GameState::restore(&save.path).expect("restore original").turn());
+    }
+
+    #[test]
+    fn restore_at_turn_after_compaction() {
+        let save = TempSave::new("compacted");
+        let (mut game, _) = new_game(&save, 3, 3, 3);
+
+        // Play until the log has been compacted a couple of times.
+        let mut food_by_turn = vec![game.food()];
+        while FileLogStore::history_paths(&save.path).len() < 2 {
+            game.advance_turn().expect("advance_turn");
+            food_by_turn.push(game.food());
+        }
+        game.close().expect("close");
+
+        // Restoring carries on with the same log, rather than compacting it
+        // again.
+        let mut game = super::GameState::restore(&save.path).expect("restore");
+        game.advance_turn().expect("advance_turn");
+        food_by_turn.push(game.food());
+        game.close().expect("close");
+        assert_eq!(2, FileLogStore::history_paths(&save.path).len());
+
+        for &turn in &[1, 5, food_by_turn.len() as i32 - 1] {
+            let past = super::GameState::restore_at_turn(&save.path, turn).expect("restore_at_turn");
+            assert_eq!(food_by_turn[turn as usize], past.food(), "food at turn {}", turn);
+        }
+        let verification = super::GameState::verify_save(&save.path).expect("verify_save");
+        assert!(verification.divergence.is_none(), "{}", verification.divergence.unwrap());
+    }
+
+    #[test]
+    fn starvation() {
+        let save = TempSave::new("starvation");
+        let (mut game, _) = new_game(&save, 12, 4, 4);
+        game.set_snapshot_interval(Some(1));
+
+        // Nobody farms, so the initial food runs out and everyone starves.
+        let mut prompts = vec![];
+        while game.population().characters().len() > 0 {
+            assert!(game.turn() < 50, "everyone should have starved by now");
+            prompts.extend(game.advance_turn().expect("advance_turn").into_iter().filter(|p| match p {
+                super::Prompt::AsylumSeeker(_) => false,
+                _ => true,
+            }).map(|p| format!("{:?}", p)));
+        }
+        assert!(prompts[0].starts_with("FoodRunningLow"), "{:?}", prompts);
+        assert!(prompts.iter().any(|p| p == "FoodShortage { turns_starving: 1 }"), "{:?}", prompts);
+        assert!(prompts.iter().any(|p| p.starts_with("CharacterDied")), "{:?}", prompts);
+        assert_eq!(crate::types::Millis::zero(), game.food());
+        assert!(game.workforce().unassigned().members().is_empty());
+
+        let restored = super::GameState::restore(&save.path).expect("restore");
+        assert_eq!(game.turn(), restored.turn());
+        assert_eq!(0, restored.population().characters().len());
+        let verification = super::GameState::verify_save(&save.path).expect("verify_save");
+        assert!(verification.divergence.is_none(), "{}", verification.divergence.unwrap());
+    }
+
+    #[test]
+    fn death_of_old_age() {
+        use crate::character::ADULT_AGE;
+        use crate::workforce::Job;
+
+        let save = TempSave::new("old_age");
+        // Old enough that the first character is sure to die at the end of
+        // the year, while the others have plenty of years left.
+        let (mut game, ids) = begin(&save, GameSpec{
+            initial_potential_characters: 3,
+            initial_characters: 3,
+            seed: 4,
+            save_format: LogFormat::Json,
+        }, &|c| c.set_age(if c.id() == CharacterId(0) { 100 } else { ADULT_AGE }));
+        game.execute_command(&super::UserCommand::AssignToTeam{cid: ids[0], job: Job::FARMER}).expect("assign");
+        game.execute_command(&super::UserCommand::AssignToTeam{cid: ids[1], job: Job::FARMER}).expect("assign");
+        game.advance_turn().expect("advance_turn");
+        assert_eq!(1, game.population().rapport_tracker().turns_on_same_team(&ids[0], &ids[1]));
+
+        let died = (1..crate::aging::TURNS_PER_YEAR).flat_map(|_| game.advance_turn().expect("advance_turn"))
+            .filter_map(|p| match p {
+                super::Prompt::CharacterDied(c, reason) => Some((c.id(), reason)),
+                _ => None,
+            }).collect::<Vec<_>>();
+        assert_eq!(vec![(ids[0], super::DepartureReason::OldAge)], died);
+        for state in vec![game.state().clone(), super::GameState::restore(&save.path).expect("restore").state().clone()] {
+            assert!(state.population.character_with_id(ids[0]).is_none());
+            assert!(!state.workforce.farmers().contains(&ids[0]));
+            assert_eq!(None, state.workforce.job_of(ids[0]));
+            assert_eq!(0, state.population.rapport_tracker().turns_on_same_team(&ids[0], &ids[1]));
+        }
+    }
+
+    #[test]
+    fn families() {
+        use crate::character::{ADULT_AGE, Trait, TraitRating};
+        use crate::population::Household;
+        use crate::workforce::Job;
+
+        let save = TempSave::new("families");
+        // Young enough to have children for the whole test, and good enough
+        // farmers that nobody starves.
+        let (mut game, ids) = begin(&save, GameSpec{
+            initial_potential_characters: 2,
+            initial_characters: 2,
+            seed: 0,
+            save_format: LogFormat::Json,
+        }, &|c| {
+            c.set_age(ADULT_AGE);
+            for t in Trait::iter() {
+                *c.mut_trait(t) = TraitRating{value: 70, capacity: 70};
+            }
+        });
+        game.set_snapshot_interval(Some(5));
+        for &cid in &ids {
+            game.execute_command(&super::UserCommand::AssignToTeam{cid: cid, job: Job::FARMER}).expect("assign");
+        }
+
+        // Working together, the two set up a household, and sooner or later
+        // have a child.
+        let mut born = vec![];
+        for _ in 0..100 {
+            for prompt in game.advance_turn().expect("advance_turn") {
+                if let super::Prompt::Born(c) = prompt {
+                    born.push(c.id());
+                }
+            }
+            if !born.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(&vec![Household::new(ids[0], ids[1])], game.population().households());
+        assert_eq!(1, born.len());
+        for cid in &born {
+            assert!(!game.workforce().contains(*cid));
+            assert!(game.execute_command(&super::UserCommand::AssignToTeam{cid: *cid, job: Job::FARMER}).is_err());
+        }
+
+        // A child about to come of age joins the workforce at the end of the year.
+        let parents = (game.population().characters()[0].clone(), game.population().characters()[1].clone());
+        let mut teenager = Character::new_child(game.state().next_valid_cid, (&parents.0, &parents.1), &mut crate::types::new_rng(0));
+        teenager.set_age(ADULT_AGE - 1);
+        let teenager_id: CharacterId = teenager.id();
+        game.machine.apply(&super::MutationT::Birth{character: teenager}).expect("birth");
+        let came_of_age = (0..crate::aging::TURNS_PER_YEAR).flat_map(|_| game.advance_turn().expect("advance_turn"))
+            .filter_map(|p| match p {
+                super::Prompt::CameOfAge(c) => Some(c.id()),
+                _ => None,
+            }).collect::<Vec<_>>();
+        assert_eq!(vec![teenager_id], came_of_age);
+        assert!(game.workforce().unassigned().contains(&teenager_id));
+
+        let verification = super::GameState::verify_save(&save.path).expect("verify_save");
+        assert!(verification.divergence.is_none(), "{}", verification.divergence.unwrap());
+    }
+}
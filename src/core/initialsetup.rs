@@ -1,17 +1,21 @@
 use super::character;
 use super::gamestate;
+use super::types;
 
 pub struct InitialSetup {
     spec: gamestate::GameSpec,
+    rng: types::GameRng,
 
     pub character_candidates: Vec<character::Character>,
 }
 
 impl InitialSetup {
     pub fn new(spec: gamestate::GameSpec) -> InitialSetup {
-        let character_candidates = (0..(spec.initial_potential_characters as i64)).map(|i| character::Character::new_random(character::CharacterId(i))).collect::<Vec<character::Character>>();
+        let mut rng = types::new_rng(spec.seed);
+        let character_candidates = (0..(spec.initial_potential_characters as i64)).map(|i| character::Character::new_random(character::CharacterId(i), &mut rng)).collect::<Vec<character::Character>>();
         return InitialSetup{
             spec: spec,
+            rng: rng,
             character_candidates: character_candidates,
         }
     }
@@ -20,10 +24,11 @@ impl InitialSetup {
         return &self.spec;
     }
 
-    pub fn begin(self, selected_characters: std::collections::HashSet<character::CharacterId>, save_file: std::fs::File) -> anyhow::Result<gamestate::GameState> {
+    pub fn begin<P: AsRef<std::path::Path>>(self, selected_characters: std::collections::HashSet<character::CharacterId>, save_path: P) -> anyhow::Result<gamestate::GameState> {
         return Ok(gamestate::GameState::init(
             self.spec,
             self.character_candidates.into_iter().filter(|c| selected_characters.contains(&c.id())).collect(),
-            save_file)?);
+            self.rng,
+            save_path)?);
     }
 }
@@ -1,14 +1,24 @@
 pub mod castle;
 pub mod character;
+pub mod diff;
 pub mod gamestate;
 pub mod initialsetup;
+pub mod logformat;
+pub mod logstore;
 pub mod population;
+pub mod saveslots;
 pub mod statemachine;
 pub mod team;
+pub mod traits;
 pub mod types;
 pub mod workforce;
 
+mod aging;
 mod economy;
+mod family;
+mod migrations;
+mod morale;
+mod starvation;
 
 extern crate anyhow;
 extern crate itertools;
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+
+// How entries in a save log are encoded. JSON is easy to read when debugging;
+// CBOR is smaller and faster for long games.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LogFormat {
+    // One entry per line, as "<length> <crc32> <json>". Lines from older logs
+    // are just the JSON.
+    Json,
+    // A magic preamble, then for each entry a little-endian u32 length and
+    // u32 crc32, followed by that many bytes of CBOR.
+    Cbor,
+}
+
+impl Default for LogFormat {
+    fn default() -> LogFormat {
+        return LogFormat::Json;
+    }
+}
+
+const CBOR_MAGIC: &[u8] = b"SIMCASTLE-CBOR\n";
+
+// The entries of a log, still encoded, in order.
+pub struct DecodedLog {
+    pub format: LogFormat,
+    pub entries: Vec<Vec<u8>>,
+    // Trailing bytes that didn't form a complete entry.
+    pub torn_bytes: usize,
+}
+
+impl LogFormat {
+    pub fn detect(bytes: &[u8]) -> LogFormat {
+        if bytes.starts_with(CBOR_MAGIC) {
+            return LogFormat::Cbor;
+        }
+        return LogFormat::Json;
+    }
+
+    // Goes at the very start of every log in this format.
+    pub fn preamble(&self) -> &'static [u8] {
+        match self {
+            LogFormat::Json => b"",
+            LogFormat::Cbor => CBOR_MAGIC,
+        }
+    }
+
+    pub fn encode_entry<T: Serialize>(&self, entry: &T) -> anyhow::Result<Vec<u8>> {
+        match self {
+            LogFormat::Json => {
+                let json = serde_json::to_vec(entry)?;
+                let mut bytes = format!("{} {:08x} ", json.len(), crc32fast::hash(&json)).into_bytes();
+                bytes.extend(json);
+                bytes.push(b'\n');
+                return Ok(bytes);
+            },
+            LogFormat::Cbor => {
+                let cbor = serde_cbor::to_vec(entry)?;
+                let mut bytes = (cbor.len() as u32).to_le_bytes().to_vec();
+                bytes.extend(&crc32fast::hash(&cbor).to_le_bytes());
+                bytes.extend(cbor);
+                return Ok(bytes);
+            },
+        }
+    }
+
+    pub fn parse<T: serde::de::DeserializeOwned>(&self, entry: &[u8]) -> anyhow::Result<T> {
+        match self {
+            LogFormat::Json => return Ok(serde_json::from_slice(entry)?),
+            LogFormat::Cbor => return Ok(serde_cbor::from_slice(entry)?),
+        }
+    }
+
+    // Parses an entry into JSON's data model, which is what migrations work on.
+    pub fn parse_value(&self, entry: &[u8]) -> anyhow::Result<serde_json::Value> {
+        match self {
+            LogFormat::Json => return Ok(serde_json::from_slice(entry)?),
+            LogFormat::Cbor => return cbor_to_json(serde_cbor::from_slice(entry)?),
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> anyhow::Result<DecodedLog> {
+    let format = LogFormat::detect(bytes);
+    let bytes = &bytes[format.preamble().len()..];
+
+    let mut entries = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let framed = match format {
+            LogFormat::Json => next_json_entry(&bytes[pos..]),
+            LogFormat::Cbor => next_cbor_entry(&bytes[pos..]),
+        };
+        match framed {
+            Ok(Some((entry, len))) => {
+                entries.push(entry.to_vec());
+                pos += len;
+            },
+            Ok(None) => return Ok(DecodedLog{format: format, entries: entries, torn_bytes: bytes.len() - pos}),
+            Err(err) => return Err(anyhow::anyhow!(
+                "Log entry {} (byte offset {}) is corrupt: {}",
+                entries.len(), pos + format.preamble().len(), err)),
+        }
+    }
+    return Ok(DecodedLog{format: format, entries: entries, torn_bytes: 0});
+}
+
+// Returns the entry at the start of 'bytes' and the number of bytes it takes
+// up, or None if the bytes stop before the entry does.
+fn next_json_entry(bytes: &[u8]) -> anyhow::Result<Option<(&[u8], usize)>> {
+    let line_len = match bytes.iter().position(|&b| b == b'\n') {
+        Some(len) => len,
+        // Serialized JSON never contains a raw newline, so an entry
+        // without one is the remains of an interrupted write.
+        None => return Ok(None),
+    };
+    let line = &bytes[..line_len];
+    if line.first() == Some(&b'{') {
+        return Ok(Some((line, line_len + 1)));
+    }
+
+    let mut parts = line.splitn(3, |&b| b == b' ');
+    let (len, checksum, json) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(len), Some(checksum), Some(json)) => (len, checksum, json),
+        _ => return Err(anyhow::anyhow!("malformed entry framing")),
+    };
+    let len = std::str::from_utf8(len)?.parse::<usize>()?;
+    let checksum = u32::from_str_radix(std::str::from_utf8(checksum)?, 16)?;
+    if len != json.len() {
+        return Err(anyhow::anyhow!("expected {} bytes, found {}", len, json.len()));
+    }
+    if checksum != crc32fast::hash(json) {
+        return Err(anyhow::anyhow!("checksum mismatch"));
+    }
+    return Ok(Some((json, line_len + 1)));
+}
+
+fn next_cbor_entry(bytes: &[u8]) -> anyhow::Result<Option<(&[u8], usize)>> {
+    if bytes.len() < 8 {
+        return Ok(None);
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&bytes[0..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    let mut checksum = [0u8; 4];
+    checksum.copy_from_slice(&bytes[4..8]);
+    let checksum = u32::from_le_bytes(checksum);
+
+    if bytes.len() < 8 + len {
+        return Ok(None);
+    }
+    let cbor = &bytes[8..8 + len];
+    if checksum != crc32fast::hash(cbor) {
+        return Err(anyhow::anyhow!("checksum mismatch"));
+    }
+    return Ok(Some((cbor, 8 + len)));
+}
+
+// CBOR allows map keys that JSON doesn't (e.g. the integer ids used as keys
+// in some of our maps), so those are turned into strings, as serde_json does.
+fn cbor_to_json(v: serde_cbor::Value) -> anyhow::Result<serde_json::Value> {
+    match v {
+        serde_cbor::Value::Null => return Ok(serde_json::Value::Null),
+        serde_cbor::Value::Bool(b) => return Ok(serde_json::Value::Bool(b)),
+        serde_cbor::Value::Integer(i) => {
+            if i >= 0 && i <= u64::max_value() as i128 {
+                return Ok(serde_json::Value::from(i as u64));
+            } else if i >= i64::min_value() as i128 && i < 0 {
+                return Ok(serde_json::Value::from(i as i64));
+            }
+            return Err(anyhow::anyhow!("integer out of range: {}", i));
+        },
+        serde_cbor::Value::Float(f) => return Ok(serde_json::Value::from(f)),
+        serde_cbor::Value::Text(s) => return Ok(serde_json::Value::String(s)),
+        serde_cbor::Value::Array(vs) => return Ok(serde_json::Value::Array(
+            vs.into_iter().map(cbor_to_json).collect::<anyhow::Result<Vec<serde_json::Value>>>()?)),
+        serde_cbor::Value::Map(m) => {
+            let mut object = serde_json::Map::new();
+            for (k, v) in m {
+                let key = match k {
+                    serde_cbor::Value::Text(s) => s,
+                    serde_cbor::Value::Integer(i) => i.to_string(),
+                    other => return Err(anyhow::anyhow!("unsupported map key: {:?}", other)),
+                };
+                object.insert(key, cbor_to_json(v)?);
+            }
+            return Ok(serde_json::Value::Object(object));
+        },
+        serde_cbor::Value::Tag(_, v) => return cbor_to_json(*v),
+        other => return Err(anyhow::anyhow!("unsupported CBOR value: {:?}", other)),
+    }
+}
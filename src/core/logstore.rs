@@ -0,0 +1,708 @@
+use anyhow::Context;
+use log::*;
+
+// Somewhere to keep the bytes of a save log. Stores don't know anything about
+// how entries are framed or encoded; that's up to the statemachine::Saver.
+// They must be Send + Sync so that a game can be handed to another thread.
+pub trait LogStore: Send + Sync {
+    fn append(&mut self, bytes: &[u8]) -> anyhow::Result<()>;
+
+    fn read_all(&mut self) -> anyhow::Result<Vec<u8>>;
+
+    // Replaces everything in the store with 'bytes', and forgets any history.
+    // A crash part way through must leave either the old or the new contents
+    // readable.
+    fn rewrite(&mut self, bytes: &[u8]) -> anyhow::Result<()>;
+
+    // As rewrite(), except that stores which can keep the replaced contents
+    // around as history, at least for a while; see read_history().
+    fn compact(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        return self.rewrite(bytes);
+    }
+
+    // What the store still has of everything compact() has replaced since
+    // the last rewrite(), oldest first.
+    fn read_history(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        return Ok(vec![]);
+    }
+
+    // Hands anything buffered by the store over to the OS.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        return Ok(());
+    }
+
+    // Flushes, then waits until everything appended so far is on disk.
+    fn sync(&mut self) -> anyhow::Result<()> {
+        return self.flush();
+    }
+
+    // Syncs, and releases anything the store is holding on to. Nothing should
+    // be written to the store afterwards.
+    fn close(&mut self) -> anyhow::Result<()> {
+        return self.sync();
+    }
+}
+
+// How many compacted logs a FileLogStore keeps, by default. With the
+// game's checkpoint policy, each is at most a megabyte or so.
+const DEFAULT_MAX_HISTORY_LOGS: usize = 10;
+
+// Keeps the whole log in a single file. Appends are buffered until flushed.
+// The most recently compacted logs are kept next to it, numbered in the order
+// they were compacted (see history_path).
+pub struct FileLogStore {
+    path: std::path::PathBuf,
+    // Opened lazily, and re-opened after every rewrite.
+    file: Option<std::io::BufWriter<std::fs::File>>,
+    max_history: usize,
+}
+
+impl FileLogStore {
+    pub fn new<P: AsRef<std::path::Path>>(path: P) -> FileLogStore {
+        return FileLogStore{
+            path: path.as_ref().to_path_buf(),
+            file: None,
+            max_history: DEFAULT_MAX_HISTORY_LOGS,
+        };
+    }
+
+    // Once there are this many compacted logs, the oldest is dropped to make
+    // room for the next. 0 keeps no history at all.
+    pub fn set_max_history(&mut self, max_history: usize) {
+        self.max_history = max_history;
+    }
+
+    fn file(&mut self) -> anyhow::Result<&mut std::io::BufWriter<std::fs::File>> {
+        if self.file.is_none() {
+            self.file = Some(std::io::BufWriter::new(
+                std::fs::OpenOptions::new().create(true).append(true).open(&self.path)
+                    .with_context(|| format!("Opening {:?} as save file", self.path))?));
+        }
+        return Ok(self.file.as_mut().expect("file was just opened"));
+    }
+
+    // Where the 'n'th compacted log for 'path' is kept, counting from 1.
+    pub fn history_path(path: &std::path::Path, n: usize) -> std::path::PathBuf {
+        let mut history_path = path.to_path_buf().into_os_string();
+        history_path.push(format!(".{}", n));
+        return std::path::PathBuf::from(history_path);
+    }
+
+    // All the compacted logs kept for 'path', oldest first.
+    pub fn history_paths(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+        return FileLogStore::history_numbers(path).into_iter().map(|n| FileLogStore::history_path(path, n)).collect();
+    }
+
+    // The numbers of the compacted logs kept for 'path', in order. Older
+    // ones may have been dropped, so they don't necessarily start at 1.
+    fn history_numbers(path: &std::path::Path) -> Vec<usize> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        let prefix = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => format!("{}.", name),
+            None => return vec![],
+        };
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        let mut numbers = entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.strip_prefix(&prefix)?.parse::<usize>().ok())
+            .collect::<Vec<usize>>();
+        numbers.sort();
+        return numbers;
+    }
+}
+
+// Writes 'bytes' next to 'path' and renames it into place.
+fn replace_file(path: &std::path::Path, bytes: &[u8]) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".compacting");
+    let tmp_path = std::path::PathBuf::from(tmp_path);
+    {
+        let mut tmp_file = std::fs::File::create(&tmp_path)
+            .with_context(|| format!("Creating {:?}", tmp_path))?;
+        tmp_file.write_all(bytes)?;
+        tmp_file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Renaming {:?} to {:?}", tmp_path, path))?;
+    return sync_parent_dir(path);
+}
+
+// Makes sure a file created or renamed in a directory survives a power loss.
+#[cfg(unix)]
+fn sync_parent_dir(path: &std::path::Path) -> anyhow::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    std::fs::File::open(dir)?.sync_all().with_context(|| format!("Syncing {:?}", dir))?;
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &std::path::Path) -> anyhow::Result<()> {
+    return Ok(());
+}
+
+impl LogStore for FileLogStore {
+    fn append(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        use std::io::Write;
+        self.file()?.write_all(bytes)?;
+        return Ok(());
+    }
+
+    fn read_all(&mut self) -> anyhow::Result<Vec<u8>> {
+        return Ok(std::fs::read(&self.path)
+                  .with_context(|| format!("Opening {:?} for restore", self.path))?);
+    }
+
+    fn rewrite(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        // Anything still buffered is superseded by 'bytes', so it's thrown
+        // away rather than flushed into the old log.
+        if let Some(file) = self.file.take() {
+            let _ = file.into_parts();
+        }
+        // History goes first: a crash part way through may lose it, but
+        // never leaves another log's history attached to this one.
+        for path in FileLogStore::history_paths(&self.path).into_iter().rev() {
+            std::fs::remove_file(&path).with_context(|| format!("Deleting {:?}", path))?;
+        }
+        return replace_file(&self.path, bytes);
+    }
+
+    fn compact(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.flush()?;
+        self.file = None;
+        let mut numbers = FileLogStore::history_numbers(&self.path);
+        if self.path.exists() && self.max_history > 0 {
+            let old = std::fs::read(&self.path).with_context(|| format!("Reading {:?}", self.path))?;
+            let next = numbers.last().map(|n| n + 1).unwrap_or(1);
+            replace_file(&FileLogStore::history_path(&self.path, next), &old)?;
+            numbers.push(next);
+        }
+        // Only once the new one is safely written are the oldest dropped.
+        for n in &numbers[..numbers.len().saturating_sub(self.max_history)] {
+            let path = FileLogStore::history_path(&self.path, *n);
+            std::fs::remove_file(&path).with_context(|| format!("Deleting {:?}", path))?;
+        }
+        return replace_file(&self.path, bytes);
+    }
+
+    fn read_history(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        return FileLogStore::history_paths(&self.path).iter()
+            .map(|path| std::fs::read(path).with_context(|| format!("Reading {:?}", path)))
+            .collect();
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        use std::io::Write;
+        if let Some(file) = self.file.as_mut() {
+            file.flush()?;
+        }
+        return Ok(());
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        self.flush()?;
+        if let Some(file) = self.file.as_mut() {
+            file.get_ref().sync_data()?;
+        }
+        return Ok(());
+    }
+}
+
+// Keeps the log in memory. Clones share the same buffer, so a test can hold
+// on to one and inspect what was written through another.
+#[derive(Clone)]
+pub struct MemoryLogStore {
+    buf: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    history: std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+}
+
+impl MemoryLogStore {
+    pub fn new() -> MemoryLogStore {
+        return MemoryLogStore{
+            buf: std::sync::Arc::new(std::sync::Mutex::new(vec![])),
+            history: std::sync::Arc::new(std::sync::Mutex::new(vec![])),
+        };
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        return self.buf.lock().expect("MemoryLogStore::lock").clone();
+    }
+}
+
+impl LogStore for MemoryLogStore {
+    fn append(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.buf.lock().expect("MemoryLogStore::lock").extend_from_slice(bytes);
+        return Ok(());
+    }
+
+    fn read_all(&mut self) -> anyhow::Result<Vec<u8>> {
+        return Ok(self.contents());
+    }
+
+    fn rewrite(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.history.lock().expect("MemoryLogStore::lock").clear();
+        *self.buf.lock().expect("MemoryLogStore::lock") = bytes.to_vec();
+        return Ok(());
+    }
+
+    fn compact(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let old = std::mem::replace(&mut *self.buf.lock().expect("MemoryLogStore::lock"), bytes.to_vec());
+        if !old.is_empty() {
+            self.history.lock().expect("MemoryLogStore::lock").push(old);
+        }
+        return Ok(());
+    }
+
+    fn read_history(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        return Ok(self.history.lock().expect("MemoryLogStore::lock").clone());
+    }
+}
+
+// Keeps the log in a directory of numbered segment files, starting a new
+// segment whenever the current one would grow past 'max_segment_bytes'.
+// Reading concatenates the segments in order.
+pub struct SegmentedLogStore {
+    dir: std::path::PathBuf,
+    max_segment_bytes: u64,
+    // The segment being appended to: (index, file, size so far).
+    current: Option<(u64, std::fs::File, u64)>,
+}
+
+impl SegmentedLogStore {
+    pub fn new<P: AsRef<std::path::Path>>(dir: P, max_segment_bytes: u64) -> SegmentedLogStore {
+        return SegmentedLogStore{
+            dir: dir.as_ref().to_path_buf(),
+            max_segment_bytes: max_segment_bytes,
+            current: None,
+        };
+    }
+
+    fn segment_path(&self, index: u64) -> std::path::PathBuf {
+        return self.dir.join(format!("{:08}.log", index));
+    }
+
+    // Indices of all existing segments, in order.
+    fn segments(&self) -> anyhow::Result<Vec<u64>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut indices = vec![];
+        for entry in std::fs::read_dir(&self.dir).with_context(|| format!("Listing {:?}", self.dir))? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(index) = name.strip_suffix(".log").and_then(|n| n.parse::<u64>().ok()) {
+                indices.push(index);
+            }
+        }
+        indices.sort();
+        return Ok(indices);
+    }
+
+    fn open_segment(&self, index: u64) -> anyhow::Result<(u64, std::fs::File, u64)> {
+        let path = self.segment_path(index);
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)
+            .with_context(|| format!("Opening segment {:?}", path))?;
+        let size = file.metadata()?.len();
+        return Ok((index, file, size));
+    }
+}
+
+impl LogStore for SegmentedLogStore {
+    fn append(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        use std::io::Write;
+
+        if self.current.is_none() {
+            std::fs::create_dir_all(&self.dir).with_context(|| format!("Creating {:?}", self.dir))?;
+            let last = self.segments()?.last().cloned().unwrap_or(0);
+            self.current = Some(self.open_segment(last)?);
+        }
+
+        let (index, _, size) = self.current.as_ref().expect("current segment");
+        if *size > 0 && size + bytes.len() as u64 > self.max_segment_bytes {
+            let next = index + 1;
+            debug!("Starting log segment {}", next);
+            // Segments are rolled rarely enough that it's cheap to make sure
+            // the finished one, and the new one's directory entry, are durable.
+            let (_, finished, _) = self.current.take().expect("current segment");
+            finished.sync_data()?;
+            self.current = Some(self.open_segment(next)?);
+            sync_parent_dir(&self.segment_path(next))?;
+        }
+
+        let (_, file, size) = self.current.as_mut().expect("current segment");
+        file.write_all(bytes)?;
+        *size += bytes.len() as u64;
+        return Ok(());
+    }
+
+    fn read_all(&mut self) -> anyhow::Result<Vec<u8>> {
+        let segments = self.segments()?;
+        if segments.is_empty() {
+            return Err(anyhow::anyhow!("No log segments in {:?}", self.dir));
+        }
+        let mut bytes = vec![];
+        for index in segments {
+            let path = self.segment_path(index);
+            bytes.extend(std::fs::read(&path).with_context(|| format!("Reading segment {:?}", path))?);
+        }
+        return Ok(bytes);
+    }
+
+    // The new contents go in a fresh segment after all the existing ones,
+    // which are only deleted once it's in place. If that's interrupted,
+    // readers see the old log followed by the new one.
+    fn rewrite(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Creating {:?}", self.dir))?;
+        self.current = None;
+
+        let old_segments = self.segments()?;
+        let next = old_segments.last().map(|i| i + 1).unwrap_or(0);
+        replace_file(&self.segment_path(next), bytes)?;
+        for index in old_segments {
+            std::fs::remove_file(self.segment_path(index))?;
+        }
+        return Ok(());
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        if let Some((_, file, _)) = self.current.as_mut() {
+            file.sync_data()?;
+        }
+        return Ok(());
+    }
+}
+
+// Hands everything to another store on a background thread, so that callers
+// don't wait for the disk. Appends and flushes that queue up while the thread
+// is busy are written as a single batch, followed by at most one flush.
+// Errors are reported by the next sync(), which, like close(), waits until
+// everything queued so far has been written.
+pub struct BackgroundLogStore {
+    sender: Option<std::sync::mpsc::Sender<Command>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    // The first error the background thread ran into, if any. Once set,
+    // nothing more is written.
+    error: std::sync::Arc<std::sync::Mutex<Option<String>>>,
+}
+
+enum Command {
+    Append(Vec<u8>),
+    Rewrite(Vec<u8>),
+    Compact(Vec<u8>),
+    Flush,
+    Sync(std::sync::mpsc::Sender<()>),
+    ReadAll(std::sync::mpsc::Sender<Result<Vec<u8>, String>>),
+    ReadHistory(std::sync::mpsc::Sender<Result<Vec<Vec<u8>>, String>>),
+}
+
+impl BackgroundLogStore {
+    pub fn new(store: Box<dyn LogStore>) -> BackgroundLogStore {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let error = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let thread_error = error.clone();
+        let thread = std::thread::Builder::new()
+            .name("log-writer".to_string())
+            .spawn(move || BackgroundLogStore::run(store, receiver, thread_error))
+            .expect("spawning log writer thread");
+        return BackgroundLogStore{
+            sender: Some(sender),
+            thread: Some(thread),
+            error: error,
+        };
+    }
+
+    fn run(mut store: Box<dyn LogStore>,
+           receiver: std::sync::mpsc::Receiver<Command>,
+           error: std::sync::Arc<std::sync::Mutex<Option<String>>>) {
+        let record = |result: anyhow::Result<()>| {
+            if let Err(err) = result {
+                let mut error = error.lock().expect("BackgroundLogStore::error");
+                if error.is_none() {
+                    error!("Background log write failed: {:?}", err);
+                    *error = Some(format!("{:?}", err));
+                }
+            }
+        };
+        let failed = || error.lock().expect("BackgroundLogStore::error").is_some();
+
+        let mut next = receiver.recv().ok();
+        while let Some(command) = next.take() {
+            match command {
+                Command::Append(_) | Command::Flush => {
+                    let mut bytes = vec![];
+                    let mut flush = false;
+                    let mut pending = Some(command);
+                    while let Some(command) = pending.take() {
+                        match command {
+                            Command::Append(more) => bytes.extend(more),
+                            Command::Flush => flush = true,
+                            other => {
+                                next = Some(other);
+                                break;
+                            },
+                        }
+                        pending = receiver.try_recv().ok();
+                    }
+                    if !failed() && !bytes.is_empty() {
+                        record(store.append(&bytes));
+                    }
+                    if !failed() && flush {
+                        record(store.flush());
+                    }
+                },
+                Command::Rewrite(bytes) => if !failed() { record(store.rewrite(&bytes)) },
+                Command::Compact(bytes) => if !failed() { record(store.compact(&bytes)) },
+                Command::Sync(done) => {
+                    if !failed() {
+                        record(store.sync());
+                    }
+                    let _ = done.send(());
+                },
+                Command::ReadAll(reply) => {
+                    let _ = reply.send(store.read_all().map_err(|err| format!("{:?}", err)));
+                },
+                Command::ReadHistory(reply) => {
+                    let _ = reply.send(store.read_history().map_err(|err| format!("{:?}", err)));
+                },
+            }
+            if next.is_none() {
+                next = receiver.recv().ok();
+            }
+        }
+    }
+
+    fn send(&self, command: Command) -> anyhow::Result<()> {
+        self.check()?;
+        return self.sender.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Log store is closed"))?
+            .send(command)
+            .map_err(|_| anyhow::anyhow!("Log writer thread has exited"));
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        match &*self.error.lock().expect("BackgroundLogStore::error") {
+            Some(err) => return Err(anyhow::anyhow!("Background log write failed: {}", err)),
+            None => return Ok(()),
+        }
+    }
+}
+
+impl LogStore for BackgroundLogStore {
+    fn append(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        return self.send(Command::Append(bytes.to_vec()));
+    }
+
+    fn read_all(&mut self) -> anyhow::Result<Vec<u8>> {
+        let (reply, response) = std::sync::mpsc::channel();
+        self.send(Command::ReadAll(reply))?;
+        return response.recv()?.map_err(|err| anyhow::anyhow!("{}", err));
+    }
+
+    fn rewrite(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        return self.send(Command::Rewrite(bytes.to_vec()));
+    }
+
+    fn compact(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        return self.send(Command::Compact(bytes.to_vec()));
+    }
+
+    fn read_history(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        let (reply, response) = std::sync::mpsc::channel();
+        self.send(Command::ReadHistory(reply))?;
+        return response.recv()?.map_err(|err| anyhow::anyhow!("{}", err));
+    }
+
+    // Doesn't wait; the wrapped store is flushed once everything before it
+    // has been written.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        return self.send(Command::Flush);
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        let (done, wait) = std::sync::mpsc::channel();
+        self.send(Command::Sync(done))?;
+        wait.recv()?;
+        return self.check();
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        if self.sender.is_none() {
+            return self.check();
+        }
+        let result = self.sync();
+        // Hanging up ends the thread once it has drained the queue.
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            thread.join().map_err(|_| anyhow::anyhow!("Log writer thread panicked"))?;
+        }
+        return result;
+    }
+}
+
+impl Drop for BackgroundLogStore {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            error!("Closing background log store: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod logstore_tests {
+    use super::BackgroundLogStore;
+    use super::FileLogStore;
+    use super::LogStore;
+    use super::MemoryLogStore;
+    use super::SegmentedLogStore;
+
+    #[test]
+    fn segmented() {
+        let dir = std::env::temp_dir().join(format!("logstore_test_segments.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut store = SegmentedLogStore::new(&dir, 10);
+        store.append(b"aaaaaa\n").expect("append");
+        store.append(b"bbbbbb\n").expect("append");
+        store.append(b"cc\n").expect("append");
+        assert_eq!(b"aaaaaa\nbbbbbb\ncc\n".to_vec(), store.read_all().expect("read_all"));
+        assert_eq!(2, std::fs::read_dir(&dir).unwrap().count());
+
+        // A new store over the same directory picks up where the old one left off.
+        let mut store = SegmentedLogStore::new(&dir, 10);
+        store.append(b"d\n").expect("append");
+        assert_eq!(b"aaaaaa\nbbbbbb\ncc\nd\n".to_vec(), store.read_all().expect("read_all"));
+
+        store.rewrite(b"eeee\n").expect("rewrite");
+        store.append(b"f\n").expect("append");
+        assert_eq!(b"eeee\nf\n".to_vec(), store.read_all().expect("read_all"));
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+
+        std::fs::remove_dir_all(&dir).expect("cleanup");
+    }
+
+    #[test]
+    fn history() {
+        let path = std::env::temp_dir().join(format!("logstore_test_history.{}", std::process::id()));
+        let mut store = FileLogStore::new(&path);
+        store.rewrite(b"a\n").expect("rewrite");
+        store.append(b"b\n").expect("append");
+        store.compact(b"c\n").expect("compact");
+        store.compact(b"d\n").expect("compact");
+        assert_eq!(b"d\n".to_vec(), store.read_all().expect("read_all"));
+        assert_eq!(vec![b"a\nb\n".to_vec(), b"c\n".to_vec()], store.read_history().expect("read_history"));
+
+        // Only the most recent are kept.
+        store.set_max_history(2);
+        store.compact(b"e\n").expect("compact");
+        assert_eq!(vec![b"c\n".to_vec(), b"d\n".to_vec()], store.read_history().expect("read_history"));
+        assert_eq!(vec![FileLogStore::history_path(&path, 2), FileLogStore::history_path(&path, 3)],
+                   FileLogStore::history_paths(&path));
+        store.set_max_history(0);
+        store.compact(b"f\n").expect("compact");
+        assert!(store.read_history().expect("read_history").is_empty());
+
+        // Starting afresh forgets the history.
+        store.set_max_history(2);
+        store.compact(b"g\n").expect("compact");
+        store.rewrite(b"h\n").expect("rewrite");
+        assert!(store.read_history().expect("read_history").is_empty());
+        assert!(FileLogStore::history_paths(&path).is_empty());
+
+        std::fs::remove_file(&path).expect("cleanup");
+    }
+
+    #[test]
+    fn background() {
+        let inner = MemoryLogStore::new();
+        let mut store = BackgroundLogStore::new(Box::new(inner.clone()));
+        for i in 0..100 {
+            store.append(format!("{}\n", i).as_bytes()).expect("append");
+        }
+        store.sync().expect("sync");
+        let expected = (0..100).map(|i| format!("{}\n", i)).collect::<String>();
+        assert_eq!(expected.as_bytes().to_vec(), inner.contents());
+
+        store.rewrite(b"a\n").expect("rewrite");
+        store.append(b"b\n").expect("append");
+        assert_eq!(b"a\nb\n".to_vec(), store.read_all().expect("read_all"));
+        store.close().expect("close");
+        assert!(store.append(b"c\n").is_err(), "appending after close should fail");
+    }
+
+    #[test]
+    fn background_batching() {
+        // Counts the appends and flushes that reach it, leaving sync() out of
+        // it. Its first rewrite() blocks until 'gate' opens, so that commands
+        // queue up behind it.
+        struct CountingStore {
+            gate: Option<std::sync::Mutex<std::sync::mpsc::Receiver<()>>>,
+            counts: std::sync::Arc<std::sync::Mutex<(usize, usize)>>,
+        }
+        impl LogStore for CountingStore {
+            fn append(&mut self, _bytes: &[u8]) -> anyhow::Result<()> {
+                self.counts.lock().unwrap().0 += 1;
+                return Ok(());
+            }
+            fn read_all(&mut self) -> anyhow::Result<Vec<u8>> {
+                return Ok(vec![]);
+            }
+            fn rewrite(&mut self, _bytes: &[u8]) -> anyhow::Result<()> {
+                if let Some(gate) = self.gate.take() {
+                    gate.lock().unwrap().recv().expect("gate");
+                }
+                return Ok(());
+            }
+            fn flush(&mut self) -> anyhow::Result<()> {
+                self.counts.lock().unwrap().1 += 1;
+                return Ok(());
+            }
+            fn sync(&mut self) -> anyhow::Result<()> {
+                return Ok(());
+            }
+        }
+
+        let (open, gate) = std::sync::mpsc::channel();
+        let counts = std::sync::Arc::new(std::sync::Mutex::new((0, 0)));
+        let mut store = BackgroundLogStore::new(Box::new(CountingStore{gate: Some(std::sync::Mutex::new(gate)), counts: counts.clone()}));
+        store.rewrite(b"").expect("rewrite");
+        for _ in 0..5 {
+            store.append(b"a\n").expect("append");
+            store.flush().expect("flush");
+        }
+        open.send(()).expect("open gate");
+        store.sync().expect("sync");
+        assert_eq!((1, 1), *counts.lock().unwrap());
+        store.close().expect("close");
+    }
+
+    #[test]
+    fn background_error() {
+        struct FailingStore;
+        impl LogStore for FailingStore {
+            fn append(&mut self, _bytes: &[u8]) -> anyhow::Result<()> {
+                return Err(anyhow::anyhow!("disk full"));
+            }
+            fn read_all(&mut self) -> anyhow::Result<Vec<u8>> {
+                return Ok(vec![]);
+            }
+            fn rewrite(&mut self, _bytes: &[u8]) -> anyhow::Result<()> {
+                return Ok(());
+            }
+        }
+
+        let mut store = BackgroundLogStore::new(Box::new(FailingStore));
+        store.append(b"a\n").expect("append is queued");
+        let err = store.sync().err().expect("sync should report the failed append");
+        assert!(format!("{}", err).contains("disk full"), "unexpected error: {}", err);
+        assert!(store.close().is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use super::character;
+use super::statemachine;
+use super::traits;
+use super::types;
+
+use anyhow::anyhow;
+
+// Bump this whenever the serialized form of GameStateT or MutationT changes,
+// and add a migration from the previous version below.
+pub const SCHEMA_VERSION: u32 = 8;
+
+pub fn schema() -> statemachine::Schema {
+    return statemachine::Schema{
+        version: SCHEMA_VERSION,
+        migrations: vec![
+            statemachine::Migration{
+                from_version: 0,
+                checkpoint: v0_add_rng_to_state,
+                delta: v0_add_rng_to_end_turn,
+            },
+            statemachine::Migration{
+                from_version: 1,
+                checkpoint: v1_add_starvation_to_state,
+                delta: v1_add_starvation_to_mutation,
+            },
+            statemachine::Migration{
+                from_version: 2,
+                checkpoint: v2_add_age_to_state,
+                delta: v2_add_aging_to_mutation,
+            },
+            statemachine::Migration{
+                from_version: 3,
+                checkpoint: v3_add_households_to_state,
+                delta: unchanged,
+            },
+            statemachine::Migration{
+                from_version: 4,
+                checkpoint: v4_add_traits_to_state,
+                delta: v4_add_traits_to_mutation,
+            },
+            statemachine::Migration{
+                from_version: 5,
+                checkpoint: v5_add_skills_to_state,
+                delta: v5_add_skills_to_mutation,
+            },
+            statemachine::Migration{
+                from_version: 6,
+                checkpoint: v6_add_leaders_to_state,
+                delta: unchanged,
+            },
+            statemachine::Migration{
+                from_version: 7,
+                checkpoint: v7_split_rapport_by_job,
+                delta: unchanged,
+            },
+        ],
+    };
+}
+
+fn as_object(v: &mut serde_json::Value) -> anyhow::Result<&mut serde_json::Map<String, serde_json::Value>> {
+    return v.as_object_mut().ok_or_else(|| anyhow!("expected an object"));
+}
+
+// Version 0 saves predate seeding, so they all continue from a fixed seed.
+fn legacy_rng() -> anyhow::Result<serde_json::Value> {
+    return Ok(serde_json::to_value(types::new_rng(0))?);
+}
+
+fn v0_add_rng_to_state(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    as_object(&mut state)?.insert("rng".to_string(), legacy_rng()?);
+    return Ok(state);
+}
+
+fn v0_add_rng_to_end_turn(mut mutation: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    if let Some(end_turn) = as_object(&mut mutation)?.get_mut("EndTurn") {
+        as_object(end_turn)?.insert("rng".to_string(), legacy_rng()?);
+    }
+    return Ok(mutation);
+}
+
+// Version 1 characters were all healthy and content.
+fn v1_add_health_and_morale(character: &mut serde_json::Value) -> anyhow::Result<()> {
+    let character = as_object(character)?;
+    character.insert("health".to_string(), serde_json::json!(character::MAX_HEALTH));
+    character.insert("morale".to_string(), serde_json::json!(character::INITIAL_MORALE));
+    return Ok(());
+}
+
+fn characters(state: &mut serde_json::Value) -> anyhow::Result<&mut Vec<serde_json::Value>> {
+    return state.pointer_mut("/population/characters").and_then(|c| c.as_array_mut())
+        .ok_or_else(|| anyhow!("expected population.characters"));
+}
+
+fn v1_add_starvation_to_state(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    for c in characters(&mut state)? {
+        v1_add_health_and_morale(c)?;
+    }
+    as_object(&mut state)?.insert("turns_starving".to_string(), serde_json::json!(0));
+    return Ok(state);
+}
+
+fn v1_add_starvation_to_mutation(mut mutation: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    if let Some(end_turn) = as_object(&mut mutation)?.get_mut("EndTurn") {
+        as_object(end_turn)?.insert("turns_starving".to_string(), serde_json::json!(0));
+    }
+    if let Some(c) = mutation.pointer_mut("/UserCommand/cmd/AddCharacter/character") {
+        v1_add_health_and_morale(c)?;
+    }
+    return Ok(mutation);
+}
+
+// Version 2 characters didn't age; they're all taken to be in their prime.
+fn v2_add_age(character: &mut serde_json::Value) -> anyhow::Result<()> {
+    as_object(character)?.insert("age".to_string(), serde_json::json!(30));
+    return Ok(());
+}
+
+fn v2_add_age_to_state(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    for c in characters(&mut state)? {
+        v2_add_age(c)?;
+    }
+    return Ok(state);
+}
+
+fn v2_add_aging_to_mutation(mut mutation: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    if let Some(delta) = mutation.pointer_mut("/UpdateCharacter/character_delta") {
+        as_object(delta)?.insert("changed_trait_capacities".to_string(), serde_json::json!({}));
+    }
+    if let Some(c) = mutation.pointer_mut("/UserCommand/cmd/AddCharacter/character") {
+        v2_add_age(c)?;
+    }
+    return Ok(mutation);
+}
+
+fn unchanged(v: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    return Ok(v);
+}
+
+fn v3_add_households_to_state(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    let population = state.get_mut("population").ok_or_else(|| anyhow!("expected population"))?;
+    as_object(population)?.insert("households".to_string(), serde_json::json!([]));
+    return Ok(state);
+}
+
+// Version 4 only had Intelligence, Strength and WorkEthic. Characters from
+// then are average at the other built-in traits, as originally defined, so
+// that migrating doesn't depend on which traits have been loaded since.
+fn v4_add_traits(character: &mut serde_json::Value) -> anyhow::Result<()> {
+    let character_traits = character.get_mut("traits").ok_or_else(|| anyhow!("expected traits"))?;
+    let character_traits = as_object(character_traits)?;
+    for def in traits::built_in_definitions() {
+        if !character_traits.contains_key(&def.name) {
+            character_traits.insert(def.name.clone(), serde_json::to_value(def.average_rating())?);
+        }
+    }
+    return Ok(());
+}
+
+fn v4_add_traits_to_state(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    for c in characters(&mut state)? {
+        v4_add_traits(c)?;
+    }
+    return Ok(state);
+}
+
+fn v4_add_traits_to_mutation(mut mutation: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    for path in &["/UserCommand/cmd/AddCharacter/character", "/Birth/character"] {
+        if let Some(c) = mutation.pointer_mut(path) {
+            v4_add_traits(c)?;
+        }
+    }
+    return Ok(mutation);
+}
+
+// Version 5 characters hadn't learned anything from their work.
+fn v5_add_skills(character: &mut serde_json::Value) -> anyhow::Result<()> {
+    as_object(character)?.insert("skills".to_string(), serde_json::json!({}));
+    return Ok(());
+}
+
+fn v5_add_skills_to_state(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    for c in characters(&mut state)? {
+        v5_add_skills(c)?;
+    }
+    return Ok(state);
+}
+
+fn v5_add_skills_to_mutation(mut mutation: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    for path in &["/UserCommand/cmd/AddCharacter/character", "/Birth/character"] {
+        if let Some(c) = mutation.pointer_mut(path) {
+            v5_add_skills(c)?;
+        }
+    }
+    return Ok(mutation);
+}
+
+// Version 6 teams had no leaders.
+fn v6_add_leaders_to_state(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    let workforce = as_object(state.get_mut("workforce").ok_or_else(|| anyhow!("expected workforce"))?)?;
+    as_object(workforce.get_mut("unassigned").ok_or_else(|| anyhow!("expected workforce.unassigned"))?)?
+        .insert("leader".to_string(), serde_json::Value::Null);
+    let teams = as_object(workforce.get_mut("teams").ok_or_else(|| anyhow!("expected workforce.teams"))?)?;
+    for (_, team) in teams.iter_mut() {
+        as_object(team)?.insert("leader".to_string(), serde_json::Value::Null);
+    }
+    return Ok(state);
+}
+
+// Version 7 only tracked rapport between farmers.
+fn v7_split_rapport_by_job(mut state: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    let rapport = state.pointer_mut("/population/rapport_tracker")
+        .ok_or_else(|| anyhow!("expected population.rapport_tracker"))?;
+    let rapport = as_object(rapport)?;
+    let farmers = rapport.remove("turns_on_same_team").ok_or_else(|| anyhow!("expected turns_on_same_team"))?;
+    rapport.insert("turns_on_same_team".to_string(), serde_json::json!({"FARMER": farmers}));
+    return Ok(state);
+}
@@ -0,0 +1,158 @@
+use super::character;
+use super::population;
+use super::workforce;
+
+use rand::Rng;
+
+// Each turn, a character's morale moves up to MORALE_DRIFT_PER_TURN towards a
+// target set by their circumstances, so it takes a while for hard times to
+// wear people down (or good times to cheer them up).
+const MORALE_DRIFT_PER_TURN: i32 = 5;
+
+// Contributions to the target, relative to a baseline of INITIAL_MORALE.
+const FOOD_SURPLUS: i32 = 10;
+const FOOD_DWINDLING: i32 = -10;
+const STARVING: i32 = -20;
+const IN_HOUSEHOLD: i32 = 5;
+const IDLE: i32 = -5;
+const WORKING_WHILE_WEAK: i32 = -10;
+const WEAK_HEALTH: i32 = 50;
+// Per turn spent working with each teammate, on average, up to
+// MAX_RAPPORT_BONUS.
+const RAPPORT_BONUS_PER_TURN: f32 = 0.5;
+const MAX_RAPPORT_BONUS: i32 = 10;
+const PER_LOSS_THIS_TURN: i32 = -10;
+const PER_BIRTH_THIS_TURN: i32 = 5;
+
+// Characters whose morale has sunk below DEPARTURE_MORALE may leave, and the
+// castle grows restless when its average morale is below UNREST_MORALE.
+const DEPARTURE_MORALE: i32 = 15;
+const DEPARTURE_PROBABILITY: f64 = 0.2;
+pub const UNREST_MORALE: i32 = 30;
+
+// What happened this turn, castle-wide.
+pub struct Circumstances {
+    pub food_surplus: bool,
+    pub starving: bool,
+    // Characters who died or left, and were born.
+    pub losses: usize,
+    pub births: usize,
+}
+
+pub struct Consequences {
+    pub character_deltas: Vec<character::CharacterDelta>,
+    pub left: Vec<character::CharacterId>,
+}
+
+fn target_morale(c: &character::Character,
+                 population: &population::Population,
+                 workforce: &workforce::Workforce,
+                 circumstances: &Circumstances) -> i32 {
+    let mut target = character::INITIAL_MORALE;
+    target += if circumstances.starving {
+        STARVING
+    } else if circumstances.food_surplus {
+        FOOD_SURPLUS
+    } else {
+        FOOD_DWINDLING
+    };
+    if population.household_of(c.id()).is_some() {
+        target += IN_HOUSEHOLD;
+    }
+
+    match workforce.job_of(c.id()) {
+        Some(job) => {
+            if c.health() < WEAK_HEALTH {
+                target += WORKING_WHILE_WEAK;
+            }
+            let team = workforce.team(&job).expect("job has a team");
+            let teammates = team.members().iter().filter(|&&m| m != c.id()).collect::<Vec<_>>();
+            if !teammates.is_empty() {
+                let total = teammates.iter().map(|m| population.rapport_tracker().turns_on_same_team(&c.id(), m)).sum::<i32>();
+                let average = total as f32 / teammates.len() as f32;
+                target += std::cmp::min(MAX_RAPPORT_BONUS, (average * RAPPORT_BONUS_PER_TURN) as i32);
+            }
+        },
+        None if c.is_adult() => target += IDLE,
+        None => {},
+    }
+
+    target += PER_LOSS_THIS_TURN * circumstances.losses as i32 + PER_BIRTH_THIS_TURN * circumstances.births as i32;
+    return target.clamp(0, 100);
+}
+
+pub fn end_of_turn<R: Rng + ?Sized>(population: &population::Population,
+                                    workforce: &workforce::Workforce,
+                                    circumstances: &Circumstances,
+                                    rng: &mut R) -> Consequences {
+    let mut consequences = Consequences{character_deltas: vec![], left: vec![]};
+    for c in population.characters() {
+        let target = target_morale(c, population, workforce, circumstances);
+        let morale = c.morale() + (target - c.morale()).clamp(-MORALE_DRIFT_PER_TURN, MORALE_DRIFT_PER_TURN);
+        if morale != c.morale() {
+            consequences.character_deltas.push(character::CharacterDelta{
+                id: c.id(),
+                changed_trait_values: maplit::hashmap!{},
+                changed_trait_capacities: maplit::hashmap!{},
+                health: None,
+                morale: Some(morale),
+                age: None,
+            });
+        }
+        if c.is_adult() && morale < DEPARTURE_MORALE && rng.gen_bool(DEPARTURE_PROBABILITY) {
+            consequences.left.push(c.id());
+        }
+    }
+    return consequences;
+}
+
+// None if there's nobody to be unhappy.
+pub fn average_morale(population: &population::Population) -> Option<i32> {
+    if population.characters().is_empty() {
+        return None;
+    }
+    let total = population.characters().iter().map(|c| c.morale()).sum::<i32>();
+    return Some(total / population.characters().len() as i32);
+}
+
+#[cfg(test)]
+mod morale_tests {
+    use super::Circumstances;
+    use crate::character::{Character, CharacterId, INITIAL_MORALE};
+    use crate::population::Population;
+    use crate::types;
+    use crate::workforce::{Job, Workforce};
+
+    #[test]
+    fn drift() {
+        let mut rng = types::new_rng(4);
+        let mut population = Population::new(
+            (0..3).map(|i| Character::new_random(CharacterId(i), &mut rng)).collect());
+        let mut workforce = Workforce::new((0..3).map(CharacterId).collect());
+        workforce.assign(CharacterId(0), Job::FARMER).expect("assign");
+        workforce.assign(CharacterId(1), Job::FARMER).expect("assign");
+        for _ in 0..20 {
+            population.mut_rapport_tracker().inc_turns_on_same_team(Job::FARMER, &CharacterId(0), &CharacterId(1));
+        }
+
+        let good_times = Circumstances{food_surplus: true, starving: false, losses: 0, births: 1};
+        let consequences = super::end_of_turn(&population, &workforce, &good_times, &mut rng);
+        assert_eq!(3, consequences.character_deltas.len());
+        assert_eq!(Some(INITIAL_MORALE + 5), consequences.character_deltas[0].morale);
+        assert!(consequences.left.is_empty());
+        // Idle, but cheered by the food and the birth.
+        let idle = super::target_morale(&population.characters()[2], &population, &workforce, &good_times);
+        assert_eq!(INITIAL_MORALE + 10, idle);
+
+        let hard_times = Circumstances{food_surplus: false, starving: true, losses: 2, births: 0};
+        for c in vec![CharacterId(0), CharacterId(1), CharacterId(2)] {
+            population.mut_character_with_id(c).unwrap().set_morale(10);
+        }
+        let left = (0..20).flat_map(|_| super::end_of_turn(&population, &workforce, &hard_times, &mut rng).left)
+            .collect::<std::collections::HashSet<CharacterId>>();
+        // The farmers' rapport with each other keeps them (just) above the
+        // point of leaving.
+        assert_eq!(vec![CharacterId(2)], left.into_iter().collect::<Vec<CharacterId>>());
+        assert_eq!(Some(10), super::average_morale(&population));
+    }
+}
@@ -1,4 +1,5 @@
 use super::character;
+use super::workforce;
 
 use serde::{Deserialize, Serialize};
 
@@ -8,6 +9,26 @@ pub struct Population {
 
     // TODO(mrjones): Is this the right place for this to live?
     rapport_tracker: RapportTracker,
+
+    households: Vec<Household>,
+}
+
+// Two characters who live together, and may have children. 'a' is always the
+// lower id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Household {
+    pub a: character::CharacterId,
+    pub b: character::CharacterId,
+}
+
+impl Household {
+    pub fn new(a: character::CharacterId, b: character::CharacterId) -> Household {
+        return Household{a: std::cmp::min(a, b), b: std::cmp::max(a, b)};
+    }
+
+    pub fn contains(&self, id: character::CharacterId) -> bool {
+        return self.a == id || self.b == id;
+    }
 }
 
 impl Population {
@@ -15,6 +36,7 @@ impl Population {
         return Population{
             characters: characters,
             rapport_tracker: RapportTracker::new(),
+            households: vec![],
         };
     }
 
@@ -34,6 +56,35 @@ impl Population {
         self.characters.push(c);
     }
 
+    // Also forgets the character's rapport with everyone else, and breaks up
+    // their household.
+    pub fn remove(&mut self, cid: character::CharacterId) -> Option<character::Character> {
+        let pos = self.characters.iter().position(|c| c.id() == cid)?;
+        self.rapport_tracker.remove_character(&cid);
+        self.households.retain(|h| !h.contains(cid));
+        return Some(self.characters.remove(pos));
+    }
+
+    pub fn households(&self) -> &Vec<Household> {
+        return &self.households;
+    }
+
+    pub fn household_of(&self, cid: character::CharacterId) -> Option<&Household> {
+        return self.households.iter().find(|h| h.contains(cid));
+    }
+
+    pub fn add_household(&mut self, household: Household) -> anyhow::Result<()> {
+        for &cid in &[household.a, household.b] {
+            if self.character_with_id(cid).is_none() {
+                return Err(anyhow::anyhow!("no character with id {}", cid));
+            }
+            if self.household_of(cid).is_some() {
+                return Err(anyhow::anyhow!("character {} already has a household", cid));
+            }
+        }
+        self.households.push(household);
+        return Ok(());
+    }
 
     pub fn rapport_tracker(&self) -> &RapportTracker {
         return &self.rapport_tracker;
@@ -43,14 +94,19 @@ impl Population {
         return &mut self.rapport_tracker;
     }
 
-    pub fn compute_end_of_turn_deltas(&self) -> Vec<character::CharacterDelta> {
-        return self.characters.iter().filter_map(|c| c.compute_end_of_turn_delta()).collect();
+    pub fn compute_end_of_turn_deltas<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> Vec<character::CharacterDelta> {
+        return self.characters.iter().filter_map(|c| c.compute_end_of_turn_delta(rng)).collect();
     }
 }
 
+// Pairs lose this many turns of cotenure at a job for every turn they don't
+// spend doing it together.
+const RAPPORT_DECAY_PER_TURN: i32 = 1;
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct RapportTracker {
-    turns_on_same_team: std::collections::HashMap<String, i32>,
+    // Cotenure by job, then by pair (see pair_key).
+    turns_on_same_team: std::collections::HashMap<workforce::Job, std::collections::HashMap<String, i32>>,
 }
 
 impl RapportTracker {
@@ -60,15 +116,89 @@ impl RapportTracker {
         }
     }
 
-    pub fn inc_turns_on_same_team(&mut self, a: &character::CharacterId, b: &character::CharacterId) {
-        *self.turns_on_same_team.entry(RapportTracker::pair_key(&a, &b)).or_insert(0) += 1;
+    pub fn inc_turns_on_same_team(&mut self, job: workforce::Job, a: &character::CharacterId, b: &character::CharacterId) {
+        *self.turns_on_same_team.entry(job).or_insert_with(std::collections::HashMap::new)
+            .entry(RapportTracker::pair_key(&a, &b)).or_insert(0) += 1;
     }
 
+    // How well the pair know each other, from working together at any job.
     pub fn turns_on_same_team(&self, a: &character::CharacterId, b: &character::CharacterId) -> i32 {
-        return self.turns_on_same_team.get(&RapportTracker::pair_key(&a, &b)).map(|x| x.clone()).unwrap_or(0);
+        let key = RapportTracker::pair_key(&a, &b);
+        return self.turns_on_same_team.values().filter_map(|pairs| pairs.get(&key)).sum();
+    }
+
+    pub fn turns_on_same_team_at(&self, job: workforce::Job, a: &character::CharacterId, b: &character::CharacterId) -> i32 {
+        return self.turns_on_same_team.get(&job)
+            .and_then(|pairs| pairs.get(&RapportTracker::pair_key(&a, &b)))
+            .cloned()
+            .unwrap_or(0);
+    }
+
+    // A turn of work: each pair on a team together gets to know each other
+    // better at that job, and every other pair drifts apart at theirs.
+    pub fn work_turn(&mut self, teams: &[(workforce::Job, Vec<(character::CharacterId, character::CharacterId)>)]) {
+        let working = teams.iter()
+            .flat_map(|(job, pairs)| pairs.iter().map(move |(a, b)| (*job, RapportTracker::pair_key(a, b))))
+            .collect::<std::collections::HashSet<(workforce::Job, String)>>();
+        for (job, pairs) in self.turns_on_same_team.iter_mut() {
+            for (key, turns) in pairs.iter_mut() {
+                if !working.contains(&(*job, key.clone())) {
+                    *turns -= RAPPORT_DECAY_PER_TURN;
+                }
+            }
+            pairs.retain(|_, turns| *turns > 0);
+        }
+        self.turns_on_same_team.retain(|_, pairs| !pairs.is_empty());
+        for (job, pairs) in teams {
+            for (a, b) in pairs {
+                self.inc_turns_on_same_team(*job, a, b);
+            }
+        }
+    }
+
+    pub fn remove_character(&mut self, id: &character::CharacterId) {
+        let id = id.to_string();
+        for pairs in self.turns_on_same_team.values_mut() {
+            pairs.retain(|key, _| !key.split(':').any(|k| k == id));
+        }
     }
 
     fn pair_key(a: &character::CharacterId, b: &character::CharacterId) -> String {
         return format!("{}:{}", std::cmp::min(a, b), std::cmp::max(a, b));
     }
 }
+
+#[cfg(test)]
+mod population_tests {
+    use super::RapportTracker;
+    use crate::character::CharacterId;
+    use crate::workforce::Job;
+
+    #[test]
+    fn rapport() {
+        let (a, b, c) = (CharacterId(0), CharacterId(1), CharacterId(2));
+        let mut rapport = RapportTracker::new();
+        for _ in 0..3 {
+            rapport.work_turn(&[(Job::FARMER, vec![(a, b)]), (Job::BUILDER, vec![(b, c)])]);
+        }
+        assert_eq!(3, rapport.turns_on_same_team_at(Job::FARMER, &b, &a));
+        assert_eq!(0, rapport.turns_on_same_team_at(Job::BUILDER, &a, &b));
+        assert_eq!(3, rapport.turns_on_same_team_at(Job::BUILDER, &b, &c));
+
+        // a and b move to building together: their farming cotenure fades
+        // while their building cotenure grows.
+        rapport.work_turn(&[(Job::BUILDER, vec![(a, b)])]);
+        assert_eq!(2, rapport.turns_on_same_team_at(Job::FARMER, &a, &b));
+        assert_eq!(1, rapport.turns_on_same_team_at(Job::BUILDER, &a, &b));
+        assert_eq!(3, rapport.turns_on_same_team(&a, &b));
+        assert_eq!(2, rapport.turns_on_same_team(&b, &c));
+
+        for _ in 0..2 {
+            rapport.work_turn(&[]);
+        }
+        assert_eq!(0, rapport.turns_on_same_team(&b, &c));
+
+        rapport.remove_character(&a);
+        assert_eq!(0, rapport.turns_on_same_team(&a, &b));
+    }
+}
@@ -0,0 +1,292 @@
+use super::character;
+use super::gamestate;
+use super::initialsetup;
+use super::logstore;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+const SAVE_EXTENSION: &str = "save";
+const METADATA_EXTENSION: &str = "meta";
+
+// A summary of a save, kept next to it so that saves can be listed without
+// replaying their logs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SlotMetadata {
+    pub turn: i32,
+    pub population: usize,
+    // Seconds since the Unix epoch.
+    pub last_played: u64,
+}
+
+impl SlotMetadata {
+    pub fn of(game: &gamestate::GameState) -> SlotMetadata {
+        return SlotMetadata{
+            turn: game.turn(),
+            population: game.population().characters().len(),
+            last_played: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs()).unwrap_or(0),
+        };
+    }
+}
+
+pub struct SaveSlot {
+    pub name: String,
+    // None if the metadata is missing or unreadable, e.g. because the game
+    // crashed before it was first written.
+    pub metadata: Option<SlotMetadata>,
+}
+
+// Named saves, each a save log plus its metadata, kept together in one
+// directory.
+pub struct SaveSlots {
+    dir: std::path::PathBuf,
+}
+
+impl SaveSlots {
+    pub fn new<P: AsRef<std::path::Path>>(dir: P) -> SaveSlots {
+        return SaveSlots{dir: dir.as_ref().to_path_buf()};
+    }
+
+    pub fn dir(&self) -> &std::path::Path {
+        return &self.dir;
+    }
+
+    // Where the save log for 'name' lives, e.g. for passing to
+    // GameState::restore_at_turn.
+    pub fn save_path(&self, name: &str) -> anyhow::Result<std::path::PathBuf> {
+        validate_name(name)?;
+        return Ok(self.dir.join(format!("{}.{}", name, SAVE_EXTENSION)));
+    }
+
+    fn metadata_path(&self, name: &str) -> anyhow::Result<std::path::PathBuf> {
+        validate_name(name)?;
+        return Ok(self.dir.join(format!("{}.{}", name, METADATA_EXTENSION)));
+    }
+
+    pub fn exists(&self, name: &str) -> anyhow::Result<bool> {
+        return Ok(self.save_path(name)?.exists());
+    }
+
+    // All saves, most recently played first.
+    pub fn list(&self) -> anyhow::Result<Vec<SaveSlot>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut slots = vec![];
+        for entry in std::fs::read_dir(&self.dir).with_context(|| format!("Listing {:?}", self.dir))? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SAVE_EXTENSION) {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|n| n.to_str()) {
+                Some(name) if validate_name(name).is_ok() => name.to_string(),
+                _ => continue,
+            };
+            slots.push(SaveSlot{metadata: self.metadata(&name).ok(), name: name});
+        }
+        slots.sort_by(|a, b| {
+            let last_played = |s: &SaveSlot| s.metadata.as_ref().map(|m| m.last_played).unwrap_or(0);
+            return last_played(b).cmp(&last_played(a)).then_with(|| a.name.cmp(&b.name));
+        });
+        return Ok(slots);
+    }
+
+    pub fn metadata(&self, name: &str) -> anyhow::Result<SlotMetadata> {
+        let path = self.metadata_path(name)?;
+        let bytes = std::fs::read(&path).with_context(|| format!("Reading {:?}", path))?;
+        return Ok(serde_json::from_slice(&bytes).with_context(|| format!("Parsing {:?}", path))?);
+    }
+
+    // Starts a new game in a slot that isn't already in use.
+    pub fn create(&self,
+                  name: &str,
+                  setup: initialsetup::InitialSetup,
+                  selected_characters: std::collections::HashSet<character::CharacterId>) -> anyhow::Result<gamestate::GameState> {
+        if self.exists(name)? {
+            return Err(anyhow::anyhow!("There is already a save called {}", name));
+        }
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Creating {:?}", self.dir))?;
+        let game = setup.begin(selected_characters, self.save_path(name)?)?;
+        self.record(name, &game)?;
+        return Ok(game);
+    }
+
+    pub fn open(&self, name: &str) -> anyhow::Result<gamestate::GameState> {
+        if !self.exists(name)? {
+            return Err(anyhow::anyhow!("There is no save called {}", name));
+        }
+        let game = gamestate::GameState::restore(self.save_path(name)?)?;
+        self.record(name, &game)?;
+        return Ok(game);
+    }
+
+    // Continues 'game' in a new slot; see GameState::fork.
+    pub fn fork(&self, name: &str, game: &gamestate::GameState) -> anyhow::Result<gamestate::GameState> {
+        if self.exists(name)? {
+            return Err(anyhow::anyhow!("There is already a save called {}", name));
+        }
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Creating {:?}", self.dir))?;
+        let forked = game.fork(self.save_path(name)?)?;
+        self.record(name, &forked)?;
+        return Ok(forked);
+    }
+
+    // Updates the metadata for 'name' to describe 'game'. Should be called
+    // whenever the game has moved on, e.g. at the end of every turn.
+    pub fn record(&self, name: &str, game: &gamestate::GameState) -> anyhow::Result<()> {
+        let path = self.metadata_path(name)?;
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension(format!("{}.tmp", METADATA_EXTENSION));
+        std::fs::write(&tmp_path, serde_json::to_vec(&SlotMetadata::of(game))?)
+            .with_context(|| format!("Writing {:?}", tmp_path))?;
+        std::fs::rename(&tmp_path, &path).with_context(|| format!("Renaming {:?} to {:?}", tmp_path, path))?;
+        return Ok(());
+    }
+
+    pub fn copy(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.check_can_move(from, to)?;
+        self.clear_leftovers(to)?;
+        std::fs::copy(self.save_path(from)?, self.save_path(to)?)
+            .with_context(|| format!("Copying save {} to {}", from, to))?;
+        for (i, history) in logstore::FileLogStore::history_paths(&self.save_path(from)?).iter().enumerate() {
+            std::fs::copy(history, logstore::FileLogStore::history_path(&self.save_path(to)?, i + 1))?;
+        }
+        if self.metadata_path(from)?.exists() {
+            std::fs::copy(self.metadata_path(from)?, self.metadata_path(to)?)?;
+        }
+        return Ok(());
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        self.check_can_move(from, to)?;
+        self.clear_leftovers(to)?;
+        // History first, so that a failure part way through leaves the save
+        // itself where it was.
+        for (i, history) in logstore::FileLogStore::history_paths(&self.save_path(from)?).iter().enumerate() {
+            std::fs::rename(history, logstore::FileLogStore::history_path(&self.save_path(to)?, i + 1))?;
+        }
+        std::fs::rename(self.save_path(from)?, self.save_path(to)?)
+            .with_context(|| format!("Renaming save {} to {}", from, to))?;
+        if self.metadata_path(from)?.exists() {
+            std::fs::rename(self.metadata_path(from)?, self.metadata_path(to)?)?;
+        }
+        return Ok(());
+    }
+
+    pub fn delete(&self, name: &str) -> anyhow::Result<()> {
+        if !self.exists(name)? {
+            return Err(anyhow::anyhow!("There is no save called {}", name));
+        }
+        std::fs::remove_file(self.save_path(name)?).with_context(|| format!("Deleting save {}", name))?;
+        for history in logstore::FileLogStore::history_paths(&self.save_path(name)?) {
+            std::fs::remove_file(history)?;
+        }
+        if self.metadata_path(name)?.exists() {
+            std::fs::remove_file(self.metadata_path(name)?)?;
+        }
+        return Ok(());
+    }
+
+    fn check_can_move(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        if !self.exists(from)? {
+            return Err(anyhow::anyhow!("There is no save called {}", from));
+        }
+        if self.exists(to)? {
+            return Err(anyhow::anyhow!("There is already a save called {}", to));
+        }
+        return Ok(());
+    }
+
+    // Removes anything left in the unused slot 'name' by a save that's no
+    // longer there (e.g. history of a save deleted by hand), which would
+    // otherwise be taken for part of whatever is moved into it.
+    fn clear_leftovers(&self, name: &str) -> anyhow::Result<()> {
+        for history in logstore::FileLogStore::history_paths(&self.save_path(name)?) {
+            std::fs::remove_file(&history).with_context(|| format!("Deleting leftover {:?}", history))?;
+        }
+        if self.metadata_path(name)?.exists() {
+            std::fs::remove_file(self.metadata_path(name)?)?;
+        }
+        return Ok(());
+    }
+}
+
+// Slot names become file names, so they're kept to characters that are safe
+// everywhere.
+fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(anyhow::anyhow!(
+            "Invalid save name {:?}: use letters, digits, '-' and '_'", name));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod saveslots_tests {
+    use super::SaveSlots;
+    use crate::gamestate::{GameSpec, GameState};
+    use crate::initialsetup::InitialSetup;
+    use crate::logformat::LogFormat;
+    use crate::logstore::{FileLogStore, LogStore};
+
+    fn new_setup() -> InitialSetup {
+        return InitialSetup::new(GameSpec{
+            initial_potential_characters: 4,
+            initial_characters: 2,
+            seed: 11,
+            save_format: LogFormat::Json,
+        });
+    }
+
+    #[test]
+    fn manage_slots() {
+        let dir = std::env::temp_dir().join(format!("saveslots_test.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let slots = SaveSlots::new(&dir);
+        assert!(slots.list().expect("list").is_empty());
+
+        let setup = new_setup();
+        let selected = setup.character_candidates.iter().take(2).map(|c| c.id()).collect();
+        let mut game = slots.create("first", setup, selected).expect("create");
+        game.advance_turn().expect("advance_turn");
+        slots.record("first", &game).expect("record");
+
+        let setup = new_setup();
+        let selected = setup.character_candidates.iter().take(2).map(|c| c.id()).collect();
+        assert!(slots.create("first", setup, selected).is_err(), "create shouldn't clobber a save");
+        assert!(slots.save_path("../escape").is_err());
+
+        // A compacted save's history goes wherever the save does.
+        game.close().expect("close");
+        let path = slots.save_path("first").unwrap();
+        FileLogStore::new(&path).compact(&std::fs::read(&path).unwrap()).expect("compact");
+        slots.copy("first", "second").expect("copy");
+        slots.rename("second", "third").expect("rename");
+        assert_eq!(1, FileLogStore::history_paths(&slots.save_path("third").unwrap()).len());
+        assert!(GameState::restore_at_turn(slots.save_path("third").unwrap(), 0).is_ok());
+        assert!(slots.rename("first", "third").is_err());
+
+        // Nor is a slot's history mixed up with leftovers from an older save.
+        let leftover = FileLogStore::history_path(&slots.save_path("fourth").unwrap(), 7);
+        std::fs::write(&leftover, b"stale").expect("write");
+        slots.copy("third", "fourth").expect("copy");
+        assert_eq!(vec![FileLogStore::history_path(&slots.save_path("fourth").unwrap(), 1)],
+                   FileLogStore::history_paths(&slots.save_path("fourth").unwrap()));
+        slots.delete("fourth").expect("delete");
+        let names = slots.list().expect("list").into_iter().map(|s| s.name).collect::<Vec<String>>();
+        assert_eq!(vec!["first", "third"], names);
+
+        let metadata = slots.metadata("third").expect("metadata");
+        assert_eq!(1, metadata.turn);
+        assert_eq!(2, metadata.population);
+        assert_eq!(1, slots.open("third").expect("open").turn());
+
+        slots.delete("first").expect("delete");
+        assert!(slots.open("first").is_err());
+        assert!(FileLogStore::history_paths(&slots.save_path("first").unwrap()).is_empty());
+        assert_eq!(1, slots.list().expect("list").len());
+
+        std::fs::remove_dir_all(&dir).expect("cleanup");
+    }
+}
@@ -0,0 +1,115 @@
+use super::character;
+use super::population;
+use super::types;
+
+use rand::Rng;
+
+// What a turn with no food at all does to each character. A partial
+// shortfall does proportionally less (but never nothing).
+const HEALTH_LOST_PER_STARVING_TURN: i32 = 20;
+const WORK_ETHIC_LOST_PER_STARVING_TURN: i32 = 3;
+
+const HEALTH_RECOVERED_PER_FED_TURN: i32 = 5;
+
+// Once the castle has gone this many turns in a row without enough food, the
+// weakest may give up and leave.
+pub const STARVING_TURNS_BEFORE_DEPARTURES: i32 = 3;
+const DEPARTURE_PROBABILITY: f64 = 0.5;
+
+pub struct Consequences {
+    pub character_deltas: Vec<character::CharacterDelta>,
+    pub died: Vec<character::CharacterId>,
+    pub left: Option<character::CharacterId>,
+}
+
+// 'shortfall' is how much of this turn's 'consumed' food there wasn't, and
+// 'turns_starving' counts this turn if there was a shortfall.
+pub fn end_of_turn<R: Rng + ?Sized>(population: &population::Population,
+                                    shortfall: types::Millis,
+                                    consumed: types::Millis,
+                                    turns_starving: i32,
+                                    rng: &mut R) -> Consequences {
+    let mut consequences = Consequences{character_deltas: vec![], died: vec![], left: None};
+
+    if shortfall <= types::Millis::zero() {
+        for c in population.characters() {
+            if c.health() < character::MAX_HEALTH {
+                consequences.character_deltas.push(character::CharacterDelta{
+                    id: c.id(),
+                    changed_trait_values: maplit::hashmap!{},
+                    changed_trait_capacities: maplit::hashmap!{},
+                    health: Some(std::cmp::min(character::MAX_HEALTH, c.health() + HEALTH_RECOVERED_PER_FED_TURN)),
+                    morale: None,
+                    age: None,
+                });
+            }
+        }
+        return consequences;
+    }
+
+    let severity = if consumed > types::Millis::zero() {
+        (shortfall.to_f32() / consumed.to_f32()).min(1.0)
+    } else {
+        1.0
+    };
+    let scaled = |max_loss: i32| std::cmp::max(1, (max_loss as f32 * severity).ceil() as i32);
+
+    let mut survivors = vec![];
+    for c in population.characters() {
+        let health = std::cmp::max(0, c.health() - scaled(HEALTH_LOST_PER_STARVING_TURN));
+        let work_ethic = std::cmp::max(0, c.get_trait_value(character::Trait::WORK_ETHIC) - scaled(WORK_ETHIC_LOST_PER_STARVING_TURN));
+        consequences.character_deltas.push(character::CharacterDelta{
+            id: c.id(),
+            changed_trait_values: maplit::hashmap!{character::Trait::WORK_ETHIC => work_ethic},
+            changed_trait_capacities: maplit::hashmap!{},
+            health: Some(health),
+            // Morale suffers too, but morale::end_of_turn() takes care of that.
+            morale: None,
+            age: None,
+        });
+        if health == 0 {
+            consequences.died.push(c.id());
+        } else {
+            survivors.push((health, c.id()));
+        }
+    }
+
+    if turns_starving >= STARVING_TURNS_BEFORE_DEPARTURES && rng.gen_bool(DEPARTURE_PROBABILITY) {
+        consequences.left = survivors.into_iter().min().map(|(_, id)| id);
+    }
+    return consequences;
+}
+
+#[cfg(test)]
+mod starvation_tests {
+    use crate::character::{Character, CharacterId, MAX_HEALTH};
+    use crate::population::Population;
+    use crate::types;
+
+    #[test]
+    fn shortfall() {
+        let mut rng = types::new_rng(1);
+        let mut population = Population::new(
+            (0..3).map(|i| Character::new_random(CharacterId(i), &mut rng)).collect());
+        population.mut_character_with_id(CharacterId(1)).unwrap().set_health(2);
+
+        // Fed: the injured recover.
+        let fed = super::end_of_turn(&population, types::Millis::zero(), types::Millis::from_i32(3), 0, &mut rng);
+        assert_eq!(1, fed.character_deltas.len());
+        assert_eq!(Some(7), fed.character_deltas[0].health);
+
+        // A small shortfall still hurts everyone, and kills the weakest.
+        let hungry = super::end_of_turn(&population, types::Millis::from_f32(0.3), types::Millis::from_i32(3), 1, &mut rng);
+        assert_eq!(3, hungry.character_deltas.len());
+        assert_eq!(Some(MAX_HEALTH - 2), hungry.character_deltas[0].health);
+        assert!(hungry.character_deltas.iter().all(|d| d.morale.is_none()), "morale is left to morale::end_of_turn()");
+        assert_eq!(vec![CharacterId(1)], hungry.died);
+        assert_eq!(None, hungry.left);
+
+        // After long enough, the weakest survivor eventually leaves.
+        population.mut_character_with_id(CharacterId(2)).unwrap().set_health(50);
+        let left = (0..20).filter_map(|_| super::end_of_turn(
+            &population, types::Millis::from_i32(3), types::Millis::from_i32(3), 5, &mut rng).left).next();
+        assert_eq!(Some(CharacterId(2)), left);
+    }
+}
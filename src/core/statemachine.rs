@@ -1,130 +1,815 @@
-// TODO:
-// - Checkpoint & trim
+use super::logformat;
+use super::logstore;
 
 use serde::{Deserialize, Serialize};
 use log::*;
 
+// What an observer is told about.
+pub enum Event<'a, D> {
+    Applied(&'a D),
+    // A delta was undone (see PersistentStateMachine::revert_last_delta).
+    Reverted(&'a D),
+}
+
+// Called after every change to the state, with the state as it is after the
+// change.
+pub type Observer<S, D> = Box<dyn FnMut(&Event<D>, &S) + Send + Sync>;
+
 pub struct StateMachine<S, D> {
     state: S,
-    apply_fn: Box<dyn Fn(&mut S, &D) -> anyhow::Result<()>>,
+    apply_fn: Box<dyn Fn(&mut S, &D) -> anyhow::Result<()> + Send + Sync>,
+    observers: Vec<Observer<S, D>>,
 }
 
 impl <S, D> StateMachine<S, D> {
-    pub fn new(initial_state: S, apply_fn: Box<dyn Fn(&mut S, &D) -> anyhow::Result<()>>) -> StateMachine<S, D> {
-        return StateMachine{state: initial_state, apply_fn: apply_fn};
+    pub fn new(initial_state: S, apply_fn: Box<dyn Fn(&mut S, &D) -> anyhow::Result<()> + Send + Sync>) -> StateMachine<S, D> {
+        return StateMachine{state: initial_state, apply_fn: apply_fn, observers: vec![]};
+    }
+
+    pub fn add_observer(&mut self, observer: Observer<S, D>) {
+        self.observers.push(observer);
     }
 
     pub fn apply(&mut self, delta: &D) -> anyhow::Result<()> {
+        (*self.apply_fn)(&mut self.state, delta)?;
+        self.notify(&Event::Applied(delta));
+        return Ok(());
+    }
+
+    // Applies 'delta' without telling observers, either because they have
+    // already seen it, or because the caller will notify() them once it's
+    // safe to.
+    fn apply_quietly(&mut self, delta: &D) -> anyhow::Result<()> {
         return (*self.apply_fn)(&mut self.state, delta);
     }
 
+    fn notify(&mut self, event: &Event<D>) {
+        for observer in &mut self.observers {
+            (*observer)(event, &self.state);
+        }
+    }
+
     pub fn state(&self) -> &S {
         return &self.state;
     }
+
+    fn reset(&mut self, state: S) {
+        self.state = state;
+    }
 }
 
 #[derive(Deserialize, Serialize)]
 pub enum LogEntry<S, D>{
+    Header(LogHeader),
     Checkpoint(S),
     Delta(D),
+    // Undoes the most recent delta that hasn't already been undone, as long
+    // as it came after the most recent checkpoint.
+    Revert,
+    // A copy of the state at this point in the log. Unlike a checkpoint,
+    // recovery ignores it; it's only there for verify() to compare against.
+    Snapshot(S),
+}
+
+// Written at the start of every log. Logs from before headers existed are
+// treated as version 0.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct LogHeader {
+    pub version: u32,
+}
+
+// Upgrades a single serialized checkpoint or delta from 'from_version' to
+// 'from_version + 1'.
+pub struct Migration {
+    pub from_version: u32,
+    pub checkpoint: fn(serde_json::Value) -> anyhow::Result<serde_json::Value>,
+    pub delta: fn(serde_json::Value) -> anyhow::Result<serde_json::Value>,
+}
+
+// The current version of S and D, plus everything needed to upgrade older
+// logs to it.
+pub struct Schema {
+    pub version: u32,
+    pub migrations: Vec<Migration>,
+}
+
+impl Schema {
+    fn migrate_entry<S: serde::de::DeserializeOwned, D: serde::de::DeserializeOwned>(
+        &self, raw: &[u8], format: logformat::LogFormat, from_version: u32) -> anyhow::Result<LogEntry<S, D>> {
+        use anyhow::Context;
+
+        if from_version == self.version {
+            return format.parse(raw);
+        }
+
+        let mut entry = format.parse_value(raw)?;
+        if !entry.is_object() || entry.get("Header").is_some() {
+            return Ok(serde_json::from_value(entry)?);
+        }
+        for version in from_version..self.version {
+            let migration = self.migrations.iter().find(|m| m.from_version == version)
+                .ok_or_else(|| anyhow::anyhow!("No migration from schema version {}", version))?;
+            let object = entry.as_object_mut()
+                .ok_or_else(|| anyhow::anyhow!("Log entry is not an object"))?;
+            for key in &["Checkpoint", "Snapshot"] {
+                if let Some(cp) = object.remove(*key) {
+                    object.insert(key.to_string(), (migration.checkpoint)(cp)
+                                  .with_context(|| format!("Migrating {} from version {}", key, version))?);
+                }
+            }
+            if let Some(d) = object.remove("Delta") {
+                object.insert("Delta".to_string(), (migration.delta)(d)
+                              .with_context(|| format!("Migrating delta from version {}", version))?);
+            }
+        }
+        return Ok(serde_json::from_value(entry)?);
+    }
+}
+
+// How hard the Saver works to make sure entries survive a crash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    // Entries are written out whenever the store decides to.
+    None,
+    // Every entry is handed to the OS as soon as it's appended, so it
+    // survives the process crashing, though not necessarily a power loss.
+    FlushPerEntry,
+    // As FlushPerEntry, and additionally waits for everything to reach the
+    // disk at each commit() (e.g. at the end of every turn).
+    SyncOnCommit,
 }
 
 pub struct Saver<S: serde::Serialize + Clone, D: serde::Serialize + Clone> {
-    sink: std::rc::Rc<std::sync::Mutex<dyn std::io::Write>>,
+    store: Box<dyn logstore::LogStore>,
+    version: u32,
+    format: logformat::LogFormat,
+    durability: Durability,
     // https://doc.rust-lang.org/std/marker/struct.PhantomData.html#examples
     phantom_s: std::marker::PhantomData<S>,
     phantom_d: std::marker::PhantomData<D>,
 }
 
 impl <S: serde::Serialize + Clone, D: serde::Serialize + Clone> Saver<S, D> {
-    pub fn new(sink: std::rc::Rc<std::sync::Mutex<dyn std::io::Write>>) -> Saver<S, D> {
+    pub fn new(store: Box<dyn logstore::LogStore>, version: u32) -> Saver<S, D> {
         return Saver{
-            sink: sink,
+            store: store,
+            version: version,
+            format: logformat::LogFormat::Json,
+            durability: Durability::FlushPerEntry,
             phantom_s: std::marker::PhantomData,
             phantom_d: std::marker::PhantomData,
         };
     }
 
-    pub fn append_checkpoint(&mut self, checkpoint: &S) -> anyhow::Result<()> {
+    // Returns the number of bytes written.
+    pub fn append_checkpoint(&mut self, checkpoint: &S) -> anyhow::Result<usize> {
         let e = LogEntry::<S, D>::Checkpoint(checkpoint.clone());
-        let as_json = serde_json::to_string(&e)?;
-        let mut sink = self.sink.lock().expect("checkpoint::lock");
-        sink.write(as_json.as_bytes())?;
-        sink.write("\n".as_bytes())?;
-        return Ok(());
+        return self.append_entry(&e);
     }
 
-    pub fn append_delta(&mut self, delta: &D) -> anyhow::Result<()> {
+    // Returns the number of bytes written.
+    pub fn append_delta(&mut self, delta: &D) -> anyhow::Result<usize> {
         let e = LogEntry::<S, D>::Delta(delta.clone());
-        let as_json = serde_json::to_string(&e)?;
-        let mut sink = self.sink.lock().expect("delta::lock");
-        sink.write(as_json.as_bytes())?;
-        sink.write("\n".as_bytes())?;
-        return Ok(());
+        return self.append_entry(&e);
+    }
+
+    // Returns the number of bytes written.
+    pub fn append_snapshot(&mut self, state: &S) -> anyhow::Result<usize> {
+        let e = LogEntry::<S, D>::Snapshot(state.clone());
+        return self.append_entry(&e);
+    }
+
+    // Returns the number of bytes written.
+    pub fn append_revert(&mut self) -> anyhow::Result<usize> {
+        return self.append_entry(&LogEntry::<S, D>::Revert);
+    }
+
+    // Replaces everything in the log, including its history, with a header
+    // and a checkpoint. Returns the number of bytes written.
+    pub fn rewrite_with_checkpoint(&mut self, checkpoint: &S) -> anyhow::Result<usize> {
+        let bytes = self.checkpoint_log(checkpoint)?;
+        self.store.rewrite(&bytes)?;
+        return Ok(bytes.len());
+    }
+
+    // As rewrite_with_checkpoint(), but the replaced log is kept as history
+    // (see logstore::LogStore::compact). Returns the number of bytes written.
+    pub fn compact_to_checkpoint(&mut self, checkpoint: &S) -> anyhow::Result<usize> {
+        let bytes = self.checkpoint_log(checkpoint)?;
+        self.store.compact(&bytes)?;
+        return Ok(bytes.len());
+    }
+
+    fn checkpoint_log(&self, checkpoint: &S) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = self.format.preamble().to_vec();
+        bytes.extend(self.format.encode_entry(&LogEntry::<S, D>::Header(LogHeader{version: self.version}))?);
+        bytes.extend(self.format.encode_entry(&LogEntry::<S, D>::Checkpoint(checkpoint.clone()))?);
+        return Ok(bytes);
+    }
+
+    // Only takes effect from the next rewrite_with_checkpoint(), since a log
+    // can't mix formats.
+    pub fn set_format(&mut self, format: logformat::LogFormat) {
+        self.format = format;
+    }
+
+    pub fn format(&self) -> logformat::LogFormat {
+        return self.format;
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    // Moves writing to a background thread; see logstore::BackgroundLogStore.
+    pub fn write_in_background(&mut self) {
+        // The placeholder is only there while the real store is moved.
+        let store = std::mem::replace(&mut self.store, Box::new(logstore::MemoryLogStore::new()));
+        self.store = Box::new(logstore::BackgroundLogStore::new(store));
+    }
+
+    // Waits until everything appended so far is on disk, whatever the
+    // durability, and reports any error writing it.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        return self.store.sync();
+    }
+
+    // As flush(), after which nothing more can be written.
+    pub fn close(&mut self) -> anyhow::Result<()> {
+        return self.store.close();
+    }
+
+    // Marks the end of a unit of work that should survive a crash as a whole.
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        match self.durability {
+            Durability::None | Durability::FlushPerEntry => return Ok(()),
+            Durability::SyncOnCommit => return self.store.sync(),
+        }
+    }
+
+    fn append_entry(&mut self, e: &LogEntry<S, D>) -> anyhow::Result<usize> {
+        // Encoding the whole entry up front means it goes to the store in a
+        // single append.
+        let bytes = self.format.encode_entry(e)?;
+        self.store.append(&bytes)?;
+        if self.durability != Durability::None {
+            self.store.flush()?;
+        }
+        return Ok(bytes.len());
+    }
+}
+
+// What happened while recovering a log.
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryReport {
+    pub entries_read: usize,
+    // Entries that were only partially written when the log was last closed,
+    // and were dropped. PersistentStateMachine::init rewrites the log, which
+    // discards them for good.
+    pub torn_entries_dropped: usize,
+    pub torn_bytes_dropped: usize,
+    pub format: logformat::LogFormat,
+    // The schema version the log (as opposed to its history) was written
+    // with, before migration.
+    pub schema_version: u32,
+}
+
+// The result of PersistentStateMachine::verify.
+#[derive(Clone, Debug)]
+pub struct Verification {
+    // Snapshots and checkpoints that matched the replayed state.
+    pub states_checked: usize,
+    pub divergence: Option<Divergence>,
+}
+
+// Where replay first stopped matching the log. The culprit is one of the
+// deltas between 'after_entry', the last entry known to be good, and
+// 'at_entry', the snapshot or checkpoint that didn't match. The log holds no
+// other states to compare against, so only logging snapshots more often (at
+// most one per delta; see PersistentStateMachine::set_snapshot_interval)
+// narrows that down.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    pub after_entry: usize,
+    pub at_entry: usize,
+    // The first field that differs, e.g. "/population/characters/0/name".
+    pub path: String,
+    pub replayed: serde_json::Value,
+    pub logged: serde_json::Value,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Replay diverged between entries {} and {}: {} is {} after replay, but {} in the log",
+               self.after_entry, self.at_entry, self.path, self.replayed, self.logged)
+    }
+}
+
+fn compare_states<S: serde::Serialize>(replayed: &S, logged: &S, after_entry: usize, at_entry: usize) -> anyhow::Result<Option<Divergence>> {
+    let replayed = serde_json::to_value(replayed)?;
+    let logged = serde_json::to_value(logged)?;
+    return Ok(first_difference("", &replayed, &logged).map(|(path, replayed, logged)| Divergence{
+        after_entry: after_entry,
+        at_entry: at_entry,
+        path: path,
+        replayed: replayed.clone(),
+        logged: logged.clone(),
+    }));
+}
+
+// Returns the path to the first place 'a' and 'b' differ, with the values
+// found there in each.
+fn first_difference<'a>(path: &str, a: &'a serde_json::Value, b: &'a serde_json::Value)
+                        -> Option<(String, &'a serde_json::Value, &'a serde_json::Value)> {
+    use serde_json::Value;
+
+    match (a, b) {
+        (Value::Object(a_map), Value::Object(b_map)) => {
+            let mut keys = a_map.keys().chain(b_map.keys()).collect::<Vec<&String>>();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child_path = format!("{}/{}", path, key);
+                match (a_map.get(key), b_map.get(key)) {
+                    (Some(a_v), Some(b_v)) => if let Some(d) = first_difference(&child_path, a_v, b_v) {
+                        return Some(d);
+                    },
+                    _ => return Some((child_path, a_map.get(key).unwrap_or(&Value::Null), b_map.get(key).unwrap_or(&Value::Null))),
+                }
+            }
+            return None;
+        },
+        (Value::Array(a_vec), Value::Array(b_vec)) if a_vec.len() == b_vec.len() => {
+            for (i, (a_v, b_v)) in a_vec.iter().zip(b_vec.iter()).enumerate() {
+                if let Some(d) = first_difference(&format!("{}/{}", path, i), a_v, b_v) {
+                    return Some(d);
+                }
+            }
+            return None;
+        },
+        _ if a == b => return None,
+        _ => return Some((if path.is_empty() { "/".to_string() } else { path.to_string() }, a, b)),
+    }
+}
+
+// Controls how often PersistentStateMachine replaces its log with a fresh
+// checkpoint. A checkpoint is written at the first commit() after either
+// limit is reached, so that whatever was applied since the last commit can
+// still be reverted.
+#[derive(Clone, Copy, Debug)]
+pub struct CheckpointPolicy {
+    pub max_deltas: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+impl CheckpointPolicy {
+    pub fn never() -> CheckpointPolicy {
+        return CheckpointPolicy{max_deltas: None, max_bytes: None};
+    }
+
+    pub fn every_n_deltas(n: usize) -> CheckpointPolicy {
+        return CheckpointPolicy{max_deltas: Some(n), max_bytes: None};
+    }
+
+    pub fn every_n_bytes(n: usize) -> CheckpointPolicy {
+        return CheckpointPolicy{max_deltas: None, max_bytes: Some(n)};
+    }
+
+    fn should_checkpoint(&self, deltas: usize, bytes: usize) -> bool {
+        return self.max_deltas.map(|max| deltas >= max).unwrap_or(false) ||
+            self.max_bytes.map(|max| bytes >= max).unwrap_or(false);
     }
+}
 
+// Where recovery should stop replaying the log.
+pub enum StopAt<'a, S, D> {
+    End,
+    // Stop before the delta with this index, counting deltas from the start
+    // of the log.
+    DeltaIndex(usize),
+    // Stop before the first delta for which this returns true, given the
+    // state it would be applied to.
+    Before(&'a dyn Fn(&S, &D) -> bool),
+}
 
+// A state rebuilt from a checkpoint plus the deltas applied since, which is
+// enough history to revert those deltas.
+struct Replay<S, D> {
+    checkpoint: S,
+    state: S,
+    tail: Vec<D>,
+}
+
+impl <S: Clone, D> Replay<S, D> {
+    fn new(checkpoint: S) -> Replay<S, D> {
+        return Replay{
+            state: checkpoint.clone(),
+            checkpoint: checkpoint,
+            tail: vec![],
+        };
+    }
+
+    fn apply(&mut self, delta: D, apply_fn: &dyn Fn(&mut S, &D) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        (*apply_fn)(&mut self.state, &delta)?;
+        self.tail.push(delta);
+        return Ok(());
+    }
+
+    fn revert(&mut self, apply_fn: &dyn Fn(&mut S, &D) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.tail.pop().ok_or(anyhow::Error::msg("Nothing to revert since the last checkpoint"))?;
+        self.state = self.checkpoint.clone();
+        for delta in &self.tail {
+            (*apply_fn)(&mut self.state, delta)?;
+        }
+        return Ok(());
+    }
 }
 
 pub struct PersistentStateMachine<S: serde::de::DeserializeOwned + serde::Serialize + Clone, D: serde::de::DeserializeOwned + serde::Serialize + Clone> {
     machine: StateMachine<S, D>,
     saver: Saver<S, D>,
+
+    policy: CheckpointPolicy,
+    // The state as of the last checkpoint, and every delta applied since.
+    checkpoint: S,
+    tail: Vec<D>,
+    bytes_since_checkpoint: usize,
+
+    snapshot_every: Option<usize>,
+    deltas_since_snapshot: usize,
 }
 
 impl <S: serde::de::DeserializeOwned + serde::Serialize + Clone, D: serde::de::DeserializeOwned + serde::Serialize + Clone> PersistentStateMachine<S, D> {
+    // Starts a new log, with no history, from 'initial_state'.
     pub fn init(initial_state: S,
-                apply_fn: Box<dyn Fn(&mut S, &D) -> anyhow::Result<()>>,
+                apply_fn: Box<dyn Fn(&mut S, &D) -> anyhow::Result<()> + Send + Sync>,
                 mut saver: Saver<S, D>) -> anyhow::Result<PersistentStateMachine<S, D>> {
-        saver.append_checkpoint(&initial_state)?;
-        return Ok(PersistentStateMachine{
+        saver.rewrite_with_checkpoint(&initial_state)?;
+        return Ok(PersistentStateMachine::new(initial_state, apply_fn, saver));
+    }
+
+    // Recovers the log in 'saver' and carries it on, in whatever format it
+    // was written in, so that the deltas since its last checkpoint can still
+    // be reverted. The log is only compacted (keeping what it held as
+    // history, but leaving nothing to revert) if it can't simply be appended
+    // to: because it has a torn tail, or is from an older schema version.
+    pub fn resume(apply_fn: Box<dyn Fn(&mut S, &D) -> anyhow::Result<()> + Send + Sync>,
+                  mut saver: Saver<S, D>,
+                  schema: &Schema) -> anyhow::Result<(PersistentStateMachine<S, D>, RecoveryReport)> {
+        let (replay, report) = PersistentStateMachine::replay_until(
+            &mut *saver.store, &*apply_fn, schema, StopAt::End, false)?;
+        saver.set_format(report.format);
+        if report.torn_entries_dropped > 0 || report.schema_version != saver.version {
+            saver.compact_to_checkpoint(&replay.state)?;
+            return Ok((PersistentStateMachine::new(replay.state, apply_fn, saver), report));
+        }
+        let mut psm = PersistentStateMachine::new(replay.checkpoint, apply_fn, saver);
+        psm.machine.reset(replay.state);
+        psm.tail = replay.tail;
+        return Ok((psm, report));
+    }
+
+    fn new(initial_state: S,
+           apply_fn: Box<dyn Fn(&mut S, &D) -> anyhow::Result<()> + Send + Sync>,
+           saver: Saver<S, D>) -> PersistentStateMachine<S, D> {
+        return PersistentStateMachine{
+            checkpoint: initial_state.clone(),
             machine: StateMachine::new(initial_state, apply_fn),
             saver: saver,
-        });
+            policy: CheckpointPolicy::never(),
+            tail: vec![],
+            bytes_since_checkpoint: 0,
+            snapshot_every: None,
+            deltas_since_snapshot: 0,
+        };
     }
 
-    pub fn recover(lines: &mut dyn Iterator<Item=String>,
-                   apply_fn: &dyn Fn(&mut S, &D) -> anyhow::Result<()>) -> anyhow::Result<S> {
+    // See StateMachine::add_observer. Observers aren't told about anything
+    // replayed during recovery.
+    pub fn add_observer(&mut self, observer: Observer<S, D>) {
+        self.machine.add_observer(observer);
+    }
+
+    pub fn set_checkpoint_policy(&mut self, policy: CheckpointPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.saver.set_durability(durability);
+    }
+
+    // Logs a snapshot of the state every so many deltas, so that verify() can
+    // check replay against it. None (the default) turns snapshots off.
+    pub fn set_snapshot_interval(&mut self, every_n_deltas: Option<usize>) {
+        self.snapshot_every = every_n_deltas;
+        self.deltas_since_snapshot = 0;
+    }
+
+    // See Saver::commit. This is also the only time the checkpoint policy
+    // is applied.
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        if self.policy.should_checkpoint(self.tail.len(), self.bytes_since_checkpoint) {
+            self.checkpoint()?;
+        }
+        return self.saver.commit();
+    }
+
+    // See Saver::write_in_background.
+    pub fn write_in_background(&mut self) {
+        self.saver.write_in_background();
+    }
+
+    // See Saver::flush.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        return self.saver.flush();
+    }
+
+    // See Saver::close.
+    pub fn close(&mut self) -> anyhow::Result<()> {
+        return self.saver.close();
+    }
+
+    pub fn recover(store: &mut dyn logstore::LogStore,
+                   apply_fn: &dyn Fn(&mut S, &D) -> anyhow::Result<()>,
+                   schema: &Schema) -> anyhow::Result<S> {
+        return Ok(PersistentStateMachine::recover_until(store, apply_fn, schema, StopAt::End, false)?.0);
+    }
+
+    // With 'include_history', replay starts from the oldest log the store
+    // has kept (see logstore::LogStore::read_history), so that 'stop_at' can
+    // be somewhere that has since been compacted away.
+    pub fn recover_until(store: &mut dyn logstore::LogStore,
+                         apply_fn: &dyn Fn(&mut S, &D) -> anyhow::Result<()>,
+                         schema: &Schema,
+                         stop_at: StopAt<S, D>,
+                         include_history: bool) -> anyhow::Result<(S, RecoveryReport)> {
+        let (replay, report) = PersistentStateMachine::replay_until(store, apply_fn, schema, stop_at, include_history)?;
+        return Ok((replay.state, report));
+    }
+
+    fn replay_until(store: &mut dyn logstore::LogStore,
+                    apply_fn: &dyn Fn(&mut S, &D) -> anyhow::Result<()>,
+                    schema: &Schema,
+                    stop_at: StopAt<S, D>,
+                    include_history: bool) -> anyhow::Result<(Replay<S, D>, RecoveryReport)> {
         use anyhow::Context;
         debug!("Recovering...");
 
-        let head = lines.next();
-        let initial_entry: LogEntry<S, D> = serde_json::from_str(
-            &head.ok_or(anyhow::Error::msg("PSM::Recover: couldn't parse initial CP"))?)?;
+        let (entries, report) = PersistentStateMachine::read_entries(store, schema, include_history)?;
+        let mut delta_index = 0;
+        let mut replay: Option<Replay<S, D>> = None;
+        for (i, entry) in entries.into_iter().enumerate() {
+            match (entry, &mut replay) {
+                (LogEntry::Header(_), _) | (LogEntry::Snapshot(_), _) => {},
+                (LogEntry::Checkpoint(cp), _) => replay = Some(Replay::new(cp)),
+                (LogEntry::Delta(_), None) | (LogEntry::Revert, None) =>
+                    return Err(anyhow::Error::msg("log started with delta")),
+                (LogEntry::Delta(d), Some(ref mut r)) => {
+                    let stop = match stop_at {
+                        StopAt::End => false,
+                        StopAt::DeltaIndex(stop_index) => delta_index == stop_index,
+                        StopAt::Before(ref predicate) => (*predicate)(&r.state, &d),
+                    };
+                    if stop {
+                        debug!("Stopping before delta {} (line {})", delta_index, i);
+                        break;
+                    }
+                    r.apply(d, apply_fn)?;
+                    delta_index += 1;
+                },
+                (LogEntry::Revert, Some(ref mut r)) => r.revert(apply_fn)
+                    .with_context(|| format!("PSM::Recover: bad revert on line {}", i))?,
+            }
+        }
+        debug!("... done.");
 
-        let mut state = match initial_entry {
-            LogEntry::Checkpoint(cp) => cp,
-            LogEntry::Delta(_) => return Err(anyhow::Error::msg("log started with delta")),
-        };
+        let replay = replay.ok_or(anyhow::Error::msg("PSM::Recover: couldn't parse initial CP"))?;
+        return Ok((replay, report));
+    }
+
+    // Replays the whole log, history and all, calling 'visit' with each
+    // entry's index, the entry, and the state just before it (None until the
+    // first checkpoint).
+    pub fn inspect(store: &mut dyn logstore::LogStore,
+                   apply_fn: &dyn Fn(&mut S, &D) -> anyhow::Result<()>,
+                   schema: &Schema,
+                   visit: &mut dyn FnMut(usize, &LogEntry<S, D>, Option<&S>)) -> anyhow::Result<RecoveryReport> {
+        use anyhow::Context;
 
-        for entry in lines {
-            let entry_struct: LogEntry<S, D> = serde_json::from_str(&entry)
-                .with_context(|| format!("PSM::Recover: couldn't parse line"))?;
-            match entry_struct {
-                LogEntry::Checkpoint(cp) => state = cp,
-                LogEntry::Delta(d) => (*apply_fn)(&mut state, &d)?,
+        let (entries, report) = PersistentStateMachine::read_entries(store, schema, true)?;
+        let mut replay: Option<Replay<S, D>> = None;
+        for (i, entry) in entries.into_iter().enumerate() {
+            visit(i, &entry, replay.as_ref().map(|r| &r.state));
+            match (entry, &mut replay) {
+                (LogEntry::Header(_), _) | (LogEntry::Snapshot(_), _) => {},
+                (LogEntry::Checkpoint(cp), _) => replay = Some(Replay::new(cp)),
+                (LogEntry::Delta(_), None) | (LogEntry::Revert, None) =>
+                    return Err(anyhow::Error::msg("log started with delta")),
+                (LogEntry::Delta(d), Some(ref mut r)) => r.apply(d, apply_fn)?,
+                (LogEntry::Revert, Some(ref mut r)) => r.revert(apply_fn)
+                    .with_context(|| format!("PSM::inspect: bad revert on line {}", i))?,
             }
         }
-        debug!("... done.");
+        return Ok(report);
+    }
+
+    // Replays the log, history and all, checking that the replayed state
+    // matches every snapshot and every checkpoint after the first. Stops at
+    // the first that doesn't.
+    pub fn verify(store: &mut dyn logstore::LogStore,
+                  apply_fn: &dyn Fn(&mut S, &D) -> anyhow::Result<()>,
+                  schema: &Schema) -> anyhow::Result<Verification> {
+        use anyhow::Context;
+
+        let (entries, _) = PersistentStateMachine::read_entries(store, schema, true)?;
+        let mut verification = Verification{states_checked: 0, divergence: None};
+        let mut last_good_entry = 0;
+        let mut replay: Option<Replay<S, D>> = None;
+        for (i, entry) in entries.into_iter().enumerate() {
+            match (entry, &mut replay) {
+                (LogEntry::Header(_), _) => {},
+                (LogEntry::Checkpoint(cp), None) => {
+                    replay = Some(Replay::new(cp));
+                    last_good_entry = i;
+                },
+                (_, None) => return Err(anyhow::Error::msg("log started with delta")),
+                (LogEntry::Checkpoint(logged), Some(ref mut r)) => {
+                    verification.divergence = compare_states(&r.state, &logged, last_good_entry, i)?;
+                    if verification.divergence.is_some() {
+                        return Ok(verification);
+                    }
+                    verification.states_checked += 1;
+                    last_good_entry = i;
+                    // As in recovery, nothing before a checkpoint can be reverted.
+                    *r = Replay::new(logged);
+                },
+                (LogEntry::Snapshot(logged), Some(ref mut r)) => {
+                    verification.divergence = compare_states(&r.state, &logged, last_good_entry, i)?;
+                    if verification.divergence.is_some() {
+                        return Ok(verification);
+                    }
+                    verification.states_checked += 1;
+                    last_good_entry = i;
+                },
+                (LogEntry::Delta(d), Some(ref mut r)) => r.apply(d, apply_fn)?,
+                (LogEntry::Revert, Some(ref mut r)) => r.revert(apply_fn)
+                    .with_context(|| format!("PSM::verify: bad revert on line {}", i))?,
+            }
+        }
+        return Ok(verification);
+    }
+
+    // Copies the log in 'from' to 'to', re-encoded in 'format' and upgraded to
+    // the current schema version. Any torn tail, and the log's history, are
+    // left behind. Returns the number of entries written, including the new
+    // header.
+    pub fn convert(from: &mut dyn logstore::LogStore,
+                   to: &mut dyn logstore::LogStore,
+                   schema: &Schema,
+                   format: logformat::LogFormat) -> anyhow::Result<usize> {
+        let (entries, _) = PersistentStateMachine::<S, D>::read_entries(from, schema, false)?;
+
+        let mut bytes = format.preamble().to_vec();
+        bytes.extend(format.encode_entry(&LogEntry::<S, D>::Header(LogHeader{version: schema.version}))?);
+        let mut written = 1;
+        for entry in &entries {
+            if let LogEntry::Header(_) = entry {
+                continue;
+            }
+            bytes.extend(format.encode_entry(entry)?);
+            written += 1;
+        }
+        to.rewrite(&bytes)?;
+        return Ok(written);
+    }
+
+    // Decodes every complete entry in the log, migrated to the current
+    // schema version, preceded by those in its history if 'include_history'.
+    // The report describes the log itself, plus any torn entries in the
+    // history.
+    fn read_entries(store: &mut dyn logstore::LogStore,
+                    schema: &Schema,
+                    include_history: bool) -> anyhow::Result<(Vec<LogEntry<S, D>>, RecoveryReport)> {
+        let mut entries = vec![];
+        let mut report = RecoveryReport::default();
+        if include_history {
+            for log in store.read_history()? {
+                let history_report = PersistentStateMachine::decode_log(&log, schema, &mut entries)?;
+                report.torn_entries_dropped += history_report.torn_entries_dropped;
+                report.torn_bytes_dropped += history_report.torn_bytes_dropped;
+            }
+        }
+        let log_report = PersistentStateMachine::decode_log(&store.read_all()?, schema, &mut entries)?;
+        report.entries_read = entries.len();
+        report.torn_entries_dropped += log_report.torn_entries_dropped;
+        report.torn_bytes_dropped += log_report.torn_bytes_dropped;
+        report.format = log_report.format;
+        report.schema_version = log_report.schema_version;
+        return Ok((entries, report));
+    }
+
+    // Decodes one complete log onto the end of 'entries'.
+    fn decode_log(bytes: &[u8], schema: &Schema, entries: &mut Vec<LogEntry<S, D>>) -> anyhow::Result<RecoveryReport> {
+        use anyhow::Context;
+
+        let decoded = logformat::decode(bytes)?;
+        let mut report = RecoveryReport{
+            entries_read: decoded.entries.len(),
+            torn_entries_dropped: if decoded.torn_bytes > 0 { 1 } else { 0 },
+            torn_bytes_dropped: decoded.torn_bytes,
+            format: decoded.format,
+            schema_version: 0,
+        };
+        if decoded.torn_bytes > 0 {
+            warn!("Dropping a partially written entry ({} bytes) from the end of the log", decoded.torn_bytes);
+        }
 
-        return Ok(state);
+        let mut version = 0;
+        for (i, entry) in decoded.entries.iter().enumerate() {
+            let entry_struct: LogEntry<S, D> = schema.migrate_entry(entry, decoded.format, version)
+                .with_context(|| format!("Couldn't parse log entry {}", i))?;
+            if let LogEntry::Header(header) = &entry_struct {
+                if header.version > schema.version {
+                    return Err(anyhow::anyhow!(
+                        "Log has schema version {}, but only versions up to {} are supported",
+                        header.version, schema.version));
+                }
+                version = header.version;
+            }
+            entries.push(entry_struct);
+        }
+        debug!("Read {} entries (from schema version {})", decoded.entries.len(), version);
+        report.schema_version = version;
+        return Ok(report);
     }
 
+    // Observers are only told about 'delta' once it has been logged.
     pub fn apply(&mut self, delta: &D) -> anyhow::Result<()> {
-        self.machine.apply(delta)?;
-        self.saver.append_delta(delta)?;
+        self.machine.apply_quietly(delta)?;
+        self.bytes_since_checkpoint += self.saver.append_delta(delta)?;
+        self.tail.push(delta.clone());
+        self.machine.notify(&Event::Applied(delta));
+
+        if let Some(every_n_deltas) = self.snapshot_every {
+            self.deltas_since_snapshot += 1;
+            if self.deltas_since_snapshot >= every_n_deltas {
+                self.bytes_since_checkpoint += self.saver.append_snapshot(self.machine.state())?;
+                self.deltas_since_snapshot = 0;
+            }
+        }
+        return Ok(());
+    }
+
+    // The most recent delta that can still be reverted, if any.
+    pub fn last_delta(&self) -> Option<&D> {
+        return self.tail.last();
+    }
+
+    // Undoes the most recent delta by replaying everything else since the
+    // last checkpoint, and records the revert in the log.
+    pub fn revert_last_delta(&mut self) -> anyhow::Result<D> {
+        let reverted = self.tail.pop().ok_or(anyhow::Error::msg("Nothing to revert since the last checkpoint"))?;
+        self.machine.reset(self.checkpoint.clone());
+        for delta in &self.tail {
+            self.machine.apply_quietly(delta)?;
+        }
+        self.bytes_since_checkpoint += self.saver.append_revert()?;
+        self.machine.notify(&Event::Reverted(&reverted));
+        return Ok(reverted);
+    }
+
+    // Replaces the log with a checkpoint of the current state, so that
+    // recovery no longer has to replay anything that came before. The old log
+    // is kept as history, if the store can.
+    pub fn checkpoint(&mut self) -> anyhow::Result<()> {
+        debug!("Checkpointing after {} deltas ({} bytes)",
+               self.tail.len(), self.bytes_since_checkpoint);
+        self.saver.compact_to_checkpoint(self.machine.state())?;
+        self.checkpoint = self.machine.state().clone();
+        self.tail.clear();
+        self.bytes_since_checkpoint = 0;
+        self.deltas_since_snapshot = 0;
         return Ok(());
     }
 
     pub fn state(&self) -> &S {
         return self.machine.state();
     }
+
+    pub fn format(&self) -> logformat::LogFormat {
+        return self.saver.format();
+    }
 }
 
 #[cfg(test)]
 mod statemachine_tests {
+    use super::CheckpointPolicy;
+    use super::Migration;
     use super::PersistentStateMachine;
     use super::Saver;
+    use super::Schema;
 
+    use crate::logstore::{LogStore, FileLogStore, MemoryLogStore};
     use crate::serde::{Deserialize, Serialize};
 
     #[derive(Deserialize, Serialize, Copy, Clone)]
@@ -133,19 +818,27 @@ mod statemachine_tests {
     #[derive(Deserialize, Serialize, Copy, Clone)]
     struct Increment{i: i32}
 
+    fn schema() -> Schema {
+        return Schema{version: 1, migrations: vec![]};
+    }
+
+    fn store_with_lines(lines: &[&str]) -> MemoryLogStore {
+        let mut store = MemoryLogStore::new();
+        for line in lines {
+            store.append(format!("{}\n", line).as_bytes()).unwrap();
+        }
+        return store;
+    }
+
     #[test]
     fn simple() {
-        let logfile: std::rc::Rc<std::sync::Mutex<Vec<u8>>> = std::rc::Rc::new(std::sync::Mutex::new(vec![]));
+        let mut logfile = MemoryLogStore::new();
 
         let apply_fn =
             |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
 
         {
-            let saver = Saver::<Total, Increment>{
-                sink: logfile.clone(),
-                phantom_d: std::marker::PhantomData,
-                phantom_s: std::marker::PhantomData,
-            };
+            let saver = Saver::<Total, Increment>::new(Box::new(logfile.clone()), 1);
 
             let mut psm = PersistentStateMachine::init(
                 Total{v: 0},
@@ -161,11 +854,488 @@ mod statemachine_tests {
             assert_eq!(11, psm.state().v, "+10 state check");
         }
 
+        let state = PersistentStateMachine::recover(&mut logfile, &apply_fn, &schema())
+            .expect("recover");
+        assert_eq!(11, state.v);
+    }
+
+    #[test]
+    fn checkpoint_trims_file() {
         use std::io::BufRead;
 
+        let path = std::env::temp_dir().join(format!("statemachine_test_trim.{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+
+        let count_lines = |path: &std::path::Path| {
+            return std::io::BufReader::new(std::fs::File::open(path).unwrap()).lines().count();
+        };
+
+        {
+            let mut psm = PersistentStateMachine::init(
+                Total{v: 0},
+                Box::new(apply_fn),
+                Saver::new(Box::new(FileLogStore::new(&path)), 1)).expect("Valid PersistentStateMachine");
+            psm.set_checkpoint_policy(CheckpointPolicy::every_n_deltas(3));
+
+            for i in 1..=10 {
+                psm.apply(&Increment{i: i}).expect("apply");
+                psm.commit().expect("commit");
+                assert!(count_lines(&path) <= 4, "log should be trimmed every 3 deltas");
+            }
+            assert_eq!(55, psm.state().v);
+
+            // Only a commit checkpoints, so everything since can be reverted.
+            for i in 1..=5 {
+                psm.apply(&Increment{i: i}).expect("apply");
+            }
+            assert_eq!(8, count_lines(&path));
+            for _ in 1..=6 {
+                psm.revert_last_delta().expect("revert");
+            }
+            assert_eq!(45, psm.state().v);
+            psm.checkpoint().expect("checkpoint");
+        }
+
+        // Everything was folded into the last checkpoint.
+        assert_eq!(2, count_lines(&path));
+
         let state = PersistentStateMachine::recover(
-            &mut logfile.lock().unwrap().lines().map(|res| res.unwrap()), &apply_fn)
-            .expect("recover");
+            &mut FileLogStore::new(&path), &apply_fn, &schema()).expect("recover");
+        assert_eq!(45, state.v);
+
+        std::fs::remove_file(&path).expect("cleanup");
+    }
+
+    #[test]
+    fn migrate_legacy_log() {
+        // Version 0 called the fields 'total' and 'inc', and had no header.
+        let mut legacy_log = store_with_lines(&[
+            r#"{"Checkpoint":{"total":5}}"#,
+            r#"{"Delta":{"inc":2}}"#,
+            r#"{"Delta":{"inc":3}}"#,
+            r#"{"Snapshot":{"total":10}}"#,
+        ]);
+
+        fn rename(mut v: serde_json::Value, from: &str, to: &str) -> anyhow::Result<serde_json::Value> {
+            let field = v.as_object_mut().unwrap().remove(from).ok_or(anyhow::Error::msg("missing field"))?;
+            v.as_object_mut().unwrap().insert(to.to_string(), field);
+            return Ok(v);
+        }
+
+        let schema = Schema{
+            version: 1,
+            migrations: vec![Migration{
+                from_version: 0,
+                checkpoint: |v| rename(v, "total", "v"),
+                delta: |v| rename(v, "inc", "i"),
+            }],
+        };
+
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        let state = PersistentStateMachine::recover(
+            &mut legacy_log, &apply_fn, &schema).expect("recover");
+        assert_eq!(10, state.v);
+
+        // Snapshots are migrated like checkpoints.
+        let verification = PersistentStateMachine::verify(&mut legacy_log, &apply_fn, &schema).expect("verify");
+        assert_eq!(1, verification.states_checked);
+        assert!(verification.divergence.is_none());
+    }
+
+    #[test]
+    fn reject_newer_log() {
+        let mut log = store_with_lines(&[
+            r#"{"Header":{"version":2}}"#,
+            r#"{"Checkpoint":{"v":5}}"#,
+        ]);
+
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        let err = PersistentStateMachine::recover(&mut log, &apply_fn, &schema())
+            .err().expect("recover should fail");
+        assert!(format!("{}", err).contains("schema version 2"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn revert() {
+        let mut logfile = MemoryLogStore::new();
+
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+
+        {
+            let mut psm = PersistentStateMachine::init(
+                Total{v: 0},
+                Box::new(apply_fn),
+                Saver::new(Box::new(logfile.clone()), 1)).expect("Valid PersistentStateMachine");
+
+            psm.apply(&Increment{i: 1}).expect("apply 1");
+            psm.apply(&Increment{i: 10}).expect("apply 2");
+            psm.apply(&Increment{i: 100}).expect("apply 3");
+
+            assert_eq!(100, psm.revert_last_delta().expect("revert 3").i);
+            assert_eq!(11, psm.state().v);
+            assert_eq!(10, psm.revert_last_delta().expect("revert 2").i);
+            assert_eq!(1, psm.state().v);
+
+            psm.apply(&Increment{i: 1000}).expect("apply 4");
+            assert_eq!(1001, psm.state().v);
+
+            let state = PersistentStateMachine::recover(&mut logfile, &apply_fn, &schema())
+                .expect("recover");
+            assert_eq!(1001, state.v, "recovery should replay the reverts");
+
+            psm.checkpoint().expect("checkpoint");
+            assert!(psm.revert_last_delta().is_err(), "can't revert past a checkpoint");
+        }
+    }
+
+    #[test]
+    fn recover_until() {
+        use super::StopAt;
+
+        let log = store_with_lines(&[
+            r#"{"Header":{"version":1}}"#,
+            r#"{"Checkpoint":{"v":0}}"#,
+            r#"{"Delta":{"i":1}}"#,
+            r#"{"Delta":{"i":10}}"#,
+            r#"{"Delta":{"i":100}}"#,
+        ]);
+
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        let recover = |stop_at| {
+            return PersistentStateMachine::recover_until(
+                &mut log.clone(), &apply_fn, &schema(), stop_at, false).expect("recover").0.v;
+        };
+
+        assert_eq!(111, recover(StopAt::End));
+        assert_eq!(0, recover(StopAt::DeltaIndex(0)));
+        assert_eq!(11, recover(StopAt::DeltaIndex(2)));
+        assert_eq!(111, recover(StopAt::DeltaIndex(10)));
+        assert_eq!(1, recover(StopAt::Before(&|s: &Total, _: &Increment| s.v > 0)));
+    }
+
+    #[test]
+    fn history() {
+        use super::StopAt;
+
+        let log = MemoryLogStore::new();
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        {
+            let mut psm = PersistentStateMachine::init(
+                Total{v: 0}, Box::new(apply_fn), Saver::new(Box::new(log.clone()), 1)).expect("init");
+            psm.set_checkpoint_policy(CheckpointPolicy::every_n_deltas(2));
+            for i in &[1, 10, 100] {
+                psm.apply(&Increment{i: *i}).expect("apply");
+                psm.commit().expect("commit");
+            }
+        }
+        // Resuming carries on where the log left off, without compacting it,
+        // and can still revert what came since its checkpoint.
+        let resume = || {
+            return PersistentStateMachine::resume(
+                Box::new(apply_fn), Saver::new(Box::new(log.clone()), 1), &schema()).expect("resume").0;
+        };
+        let mut psm = resume();
+        assert_eq!(100, psm.revert_last_delta().expect("revert").i);
+        assert!(psm.revert_last_delta().is_err());
+        psm.apply(&Increment{i: 100}).expect("apply");
+        psm.apply(&Increment{i: 1000}).expect("apply");
+        assert_eq!(1, log.clone().read_history().expect("read_history").len());
+
+        let recover = |stop_at, include_history| {
+            return PersistentStateMachine::recover_until(
+                &mut log.clone(), &apply_fn, &schema(), stop_at, include_history).expect("recover").0.v;
+        };
+        assert_eq!(1111, recover(StopAt::End, false));
+        assert_eq!(1111, recover(StopAt::End, true));
+        assert_eq!(111, recover(StopAt::Before(&|s: &Total, _: &Increment| s.v > 100), false));
+        assert_eq!(1, recover(StopAt::Before(&|s: &Total, _: &Increment| s.v > 0), true));
+        // The checkpoints that start each compacted log line up with replay.
+        let verification = PersistentStateMachine::verify(&mut log.clone(), &apply_fn, &schema()).expect("verify");
+        assert_eq!(1, verification.states_checked);
+        assert!(verification.divergence.is_none());
+
+        // A torn tail can't be appended to, so resuming compacts it away.
+        log.clone().append(b"{\"Delta\":").expect("append");
+        resume().apply(&Increment{i: 10000}).expect("apply");
+        assert_eq!(2, log.clone().read_history().expect("read_history").len());
+        assert_eq!(11111, recover(StopAt::End, false));
+        assert_eq!(1, recover(StopAt::Before(&|s: &Total, _: &Increment| s.v > 0), true));
+
+        // Starting afresh forgets the history.
+        PersistentStateMachine::init(Total{v: 5}, Box::new(apply_fn), Saver::new(Box::new(log.clone()), 1)).expect("init");
+        assert_eq!(5, recover(StopAt::Before(&|_: &Total, _: &Increment| true), true));
+    }
+
+    #[test]
+    fn torn_tail() {
+        use super::StopAt;
+
+        let mut logfile = MemoryLogStore::new();
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+
+        {
+            let mut psm = PersistentStateMachine::init(
+                Total{v: 0},
+                Box::new(apply_fn),
+                Saver::new(Box::new(logfile.clone()), 1)).expect("Valid PersistentStateMachine");
+            psm.apply(&Increment{i: 1}).expect("apply 1");
+            psm.apply(&Increment{i: 10}).expect("apply 2");
+        }
+
+        // Simulate a crash half way through writing a third delta.
+        let complete = logfile.contents();
+        let third = crate::logformat::LogFormat::Json.encode_entry(&super::LogEntry::<Total, Increment>::Delta(Increment{i: 100})).unwrap();
+        logfile.append(&third[..third.len() / 2]).unwrap();
+
+        let (state, report) = PersistentStateMachine::recover_until(
+            &mut logfile, &apply_fn, &schema(), StopAt::End, false).expect("recover");
         assert_eq!(11, state.v);
+        assert_eq!(4, report.entries_read);
+        assert_eq!(1, report.torn_entries_dropped);
+        assert_eq!(third.len() / 2, report.torn_bytes_dropped);
+
+        // Re-initializing from the recovered state leaves a clean log.
+        PersistentStateMachine::init(state, Box::new(apply_fn), Saver::new(Box::new(logfile.clone()), 1))
+            .expect("Valid PersistentStateMachine");
+        let (_, report) = PersistentStateMachine::recover_until(
+            &mut logfile, &apply_fn, &schema(), StopAt::End, false).expect("recover");
+        assert_eq!(0, report.torn_entries_dropped);
+
+        // Corruption anywhere else is an error that points at the entry.
+        let mut lines = complete.split(|&b| b == b'\n').map(|l| l.to_vec()).collect::<Vec<Vec<u8>>>();
+        let len = lines[2].len();
+        lines[2][len - 3] = b'7';  // {"Delta":{"i":1}} -> {"Delta":{"i":7}}
+        let mut corrupted = MemoryLogStore::new();
+        corrupted.append(&lines.join(&b'\n')).unwrap();
+        let err = PersistentStateMachine::recover(&mut corrupted, &apply_fn, &schema())
+            .err().expect("recover should fail");
+        assert!(format!("{}", err).contains("Log entry 2 "), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn inspect() {
+        use super::LogEntry;
+
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        let mut log = MemoryLogStore::new();
+        {
+            let mut psm = PersistentStateMachine::init(
+                Total{v: 0}, Box::new(apply_fn), Saver::new(Box::new(log.clone()), 1))
+                .expect("Valid PersistentStateMachine");
+            psm.apply(&Increment{i: 1}).expect("apply");
+            psm.apply(&Increment{i: 10}).expect("apply");
+            psm.revert_last_delta().expect("revert");
+            psm.apply(&Increment{i: 100}).expect("apply");
+        }
+
+        let mut seen = vec![];
+        let report = PersistentStateMachine::inspect(&mut log, &apply_fn, &schema(), &mut |i, entry, state| {
+            let kind = match entry {
+                LogEntry::Header(_) => "header",
+                LogEntry::Checkpoint(_) => "checkpoint",
+                LogEntry::Delta(_) => "delta",
+                LogEntry::Revert => "revert",
+                LogEntry::Snapshot(_) => "snapshot",
+            };
+            seen.push((i, kind, state.map(|s| s.v)));
+        }).expect("inspect");
+        assert_eq!(6, report.entries_read);
+        assert_eq!(vec![(0, "header", None), (1, "checkpoint", None), (2, "delta", Some(0)),
+                        (3, "delta", Some(1)), (4, "revert", Some(11)), (5, "delta", Some(1))],
+                   seen);
+    }
+
+    #[test]
+    fn observers() {
+        use super::Event;
+
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        let mut psm = PersistentStateMachine::init(
+            Total{v: 0}, Box::new(apply_fn), Saver::new(Box::new(MemoryLogStore::new()), 1))
+            .expect("Valid PersistentStateMachine");
+
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let seen_by_observer = seen.clone();
+        psm.add_observer(Box::new(move |event: &Event<Increment>, state: &Total| {
+            seen_by_observer.lock().unwrap().push(match event {
+                Event::Applied(d) => (d.i, state.v),
+                Event::Reverted(d) => (-d.i, state.v),
+            });
+        }));
+        psm.apply(&Increment{i: 1}).expect("apply");
+        psm.apply(&Increment{i: 10}).expect("apply");
+        psm.revert_last_delta().expect("revert");
+        psm.apply(&Increment{i: 100}).expect("apply");
+
+        assert_eq!(vec![(1, 1), (10, 11), (-10, 1), (100, 101)], *seen.lock().unwrap());
+
+        // Observers don't hear about deltas that couldn't be logged.
+        struct FullLogStore {}
+        impl LogStore for FullLogStore {
+            fn append(&mut self, _bytes: &[u8]) -> anyhow::Result<()> {
+                return Err(anyhow::Error::msg("disk full"));
+            }
+            fn read_all(&mut self) -> anyhow::Result<Vec<u8>> {
+                return Ok(vec![]);
+            }
+            fn rewrite(&mut self, _bytes: &[u8]) -> anyhow::Result<()> {
+                return Ok(());
+            }
+        }
+        let mut psm = PersistentStateMachine::init(
+            Total{v: 0}, Box::new(apply_fn), Saver::new(Box::new(FullLogStore{}), 1))
+            .expect("Valid PersistentStateMachine");
+        let seen_by_observer = seen.clone();
+        psm.add_observer(Box::new(move |_event: &Event<Increment>, state: &Total| {
+            seen_by_observer.lock().unwrap().push((0, state.v));
+        }));
+        assert!(psm.apply(&Increment{i: 1}).is_err());
+        assert_eq!(4, seen.lock().unwrap().len());
+    }
+
+    #[test]
+    fn verify() {
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        let log = MemoryLogStore::new();
+        {
+            let mut psm = PersistentStateMachine::init(
+                Total{v: 0}, Box::new(apply_fn), Saver::new(Box::new(log.clone()), 1))
+                .expect("Valid PersistentStateMachine");
+            psm.set_snapshot_interval(Some(2));
+            for i in 1..=5 {
+                psm.apply(&Increment{i: i}).expect("apply");
+            }
+            psm.revert_last_delta().expect("revert");
+        }
+
+        // Snapshots don't affect recovery...
+        assert_eq!(10, PersistentStateMachine::recover(&mut log.clone(), &apply_fn, &schema()).expect("recover").v);
+
+        // ... but are checked by verify().
+        let verification = PersistentStateMachine::verify(&mut log.clone(), &apply_fn, &schema()).expect("verify");
+        assert_eq!(2, verification.states_checked);
+        assert!(verification.divergence.is_none());
+
+        // A replay that differs from what was logged is caught at the first
+        // snapshot after it goes wrong.
+        let bad_apply_fn = |state: &mut Total, delta: &Increment| {
+            state.v += if delta.i == 3 { 0 } else { delta.i };
+            return Ok(());
+        };
+        let verification = PersistentStateMachine::verify(&mut log.clone(), &bad_apply_fn, &schema()).expect("verify");
+        assert_eq!(1, verification.states_checked);
+        let divergence = verification.divergence.expect("divergence");
+        // header, checkpoint, +1, +2, snapshot, +3, +4, snapshot
+        assert_eq!((4, 7), (divergence.after_entry, divergence.at_entry));
+        assert_eq!("/v", divergence.path);
+        assert_eq!((7, 10), (divergence.replayed.as_i64().unwrap(), divergence.logged.as_i64().unwrap()));
+
+        // With a snapshot after every delta, that's the delta itself.
+        let log = MemoryLogStore::new();
+        {
+            let mut psm = PersistentStateMachine::init(
+                Total{v: 0}, Box::new(apply_fn), Saver::new(Box::new(log.clone()), 1))
+                .expect("Valid PersistentStateMachine");
+            psm.set_snapshot_interval(Some(1));
+            for i in 1..=5 {
+                psm.apply(&Increment{i: i}).expect("apply");
+            }
+        }
+        let verification = PersistentStateMachine::verify(&mut log.clone(), &bad_apply_fn, &schema()).expect("verify");
+        let divergence = verification.divergence.expect("divergence");
+        // header, checkpoint, +1, snapshot, +2, snapshot, +3, snapshot
+        assert_eq!((5, 7), (divergence.after_entry, divergence.at_entry));
+    }
+
+    #[test]
+    fn durability() {
+        use super::Durability;
+
+        let path = std::env::temp_dir().join(format!("statemachine_test_durability.{}", std::process::id()));
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        let recovered = |path: &std::path::Path| {
+            return PersistentStateMachine::recover(&mut FileLogStore::new(path), &apply_fn, &schema())
+                .expect("recover").v;
+        };
+
+        for &(durability, visible_before_close) in &[(Durability::None, false),
+                                                      (Durability::FlushPerEntry, true),
+                                                      (Durability::SyncOnCommit, true)] {
+            let mut psm = PersistentStateMachine::init(
+                Total{v: 0},
+                Box::new(apply_fn),
+                Saver::new(Box::new(FileLogStore::new(&path)), 1)).expect("Valid PersistentStateMachine");
+            psm.set_durability(durability);
+            psm.apply(&Increment{i: 5}).expect("apply");
+            psm.commit().expect("commit");
+
+            assert_eq!(if visible_before_close { 5 } else { 0 }, recovered(&path), "{:?}", durability);
+            std::mem::drop(psm);
+            assert_eq!(5, recovered(&path), "{:?}", durability);
+        }
+
+        std::fs::remove_file(&path).expect("cleanup");
+    }
+
+    #[test]
+    fn cbor() {
+        use super::StopAt;
+        use crate::logformat::LogFormat;
+
+        let apply_fn =
+            |state: &mut Total, delta: &Increment| { state.v += delta.i; return Ok(()); };
+        let cbor_log = MemoryLogStore::new();
+        {
+            let mut saver = Saver::new(Box::new(cbor_log.clone()), 1);
+            saver.set_format(LogFormat::Cbor);
+            let mut psm = PersistentStateMachine::init(Total{v: 0}, Box::new(apply_fn), saver)
+                .expect("Valid PersistentStateMachine");
+            psm.apply(&Increment{i: 1}).expect("apply");
+            psm.apply(&Increment{i: 10}).expect("apply");
+            psm.revert_last_delta().expect("revert");
+            psm.apply(&Increment{i: 100}).expect("apply");
+        }
+        assert_eq!(LogFormat::Cbor, LogFormat::detect(&cbor_log.contents()));
+
+        let (state, report) = PersistentStateMachine::recover_until(
+            &mut cbor_log.clone(), &apply_fn, &schema(), StopAt::End, false).expect("recover");
+        assert_eq!(101, state.v);
+        assert_eq!(LogFormat::Cbor, report.format);
+
+        // A torn final record is dropped, as with JSON.
+        let mut torn = cbor_log.contents();
+        torn.truncate(torn.len() - 2);
+        let mut torn_log = MemoryLogStore::new();
+        torn_log.rewrite(&torn).unwrap();
+        let (state, report) = PersistentStateMachine::<Total, Increment>::recover_until(
+            &mut torn_log, &apply_fn, &schema(), StopAt::End, false).expect("recover");
+        assert_eq!(1, state.v);
+        assert_eq!(1, report.torn_entries_dropped);
+
+        // Converting either way preserves the whole history.
+        let mut json_log = MemoryLogStore::new();
+        let written = PersistentStateMachine::<Total, Increment>::convert(
+            &mut cbor_log.clone(), &mut json_log, &schema(), LogFormat::Json).expect("convert");
+        assert_eq!(6, written);
+        assert_eq!(LogFormat::Json, LogFormat::detect(&json_log.contents()));
+        assert_eq!(101, PersistentStateMachine::recover(&mut json_log, &apply_fn, &schema()).expect("recover").v);
+
+        let mut back = MemoryLogStore::new();
+        PersistentStateMachine::<Total, Increment>::convert(
+            &mut json_log, &mut back, &schema(), LogFormat::Cbor).expect("convert");
+        assert_eq!(cbor_log.contents(), back.contents());
     }
 }
@@ -1,23 +1,53 @@
 use super::character;
+use super::population;
 
+use anyhow::anyhow;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
+// How many points of a trait make a stddev of harmony.
+const TRAIT_STDDEV: f32 = 20.0;
+
+// Why a team does (or doesn't) work well together. Each component is in
+// stddevs from average, and they add up. How long they've worked together is
+// left to cotenure, which production accounts for separately.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Harmony {
+    pub compatibility: f32,
+    pub leadership: f32,
+    // The natural leader, if any: the member with the most Leadership, if
+    // that's more than most teams' best. A designated leader's effect on
+    // production is economy's business, not harmony's.
+    pub leader: Option<character::CharacterId>,
+}
+
+impl Harmony {
+    pub fn total(&self) -> f32 {
+        return self.compatibility + self.leadership;
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Team {
-    members: std::collections::HashSet<character::CharacterId>,
+    // Ordered, so that iterating over (and serializing) a team always gives
+    // the same result, which replay depends on.
+    members: std::collections::BTreeSet<character::CharacterId>,
+    // Designated by the player; always one of the members.
+    leader: Option<character::CharacterId>,
 }
 
 impl Team {
     pub fn new() -> Team {
         return Team{
-            members: std::collections::HashSet::new(),
+            members: std::collections::BTreeSet::new(),
+            leader: None,
         };
     }
 
     pub fn new_with_ids(initial_ids: std::collections::HashSet<character::CharacterId>) -> Team {
         return Team{
-            members: initial_ids,
+            members: initial_ids.into_iter().collect(),
+            leader: None,
         }
     }
 
@@ -27,16 +57,34 @@ impl Team {
 
     pub fn remove(&mut self, id: &character::CharacterId) {
         assert!(self.members.remove(id));
+        if self.leader == Some(*id) {
+            self.leader = None;
+        }
     }
 
     pub fn contains(&self, id: &character::CharacterId) -> bool {
         return self.members.contains(id);
     }
 
-    pub fn members(&self) -> &std::collections::HashSet<character::CharacterId> {
+    pub fn members(&self) -> &std::collections::BTreeSet<character::CharacterId> {
         return &self.members;
     }
 
+    pub fn leader(&self) -> Option<character::CharacterId> {
+        return self.leader;
+    }
+
+    // None means the team has no leader.
+    pub fn set_leader(&mut self, leader: Option<character::CharacterId>) -> anyhow::Result<()> {
+        if let Some(cid) = leader {
+            if !self.contains(&cid) {
+                return Err(anyhow!("Character {} isn't on the team", cid));
+            }
+        }
+        self.leader = leader;
+        return Ok(());
+    }
+
     pub fn member_pairs(&self) -> Vec<(character::CharacterId, character::CharacterId)>{
         return self.members().iter().combinations(2).map(|v| {
             assert_eq!(2, v.len());
@@ -44,15 +92,163 @@ impl Team {
         }).collect();
     }
 
-    // Scale factor: 0.0 == average, +/- 1.0 per stddev
-    pub fn harmony(&self) -> f32 {
-        // Factors: turns working together
-        // Leadership:
-        // Trait matching:
-        return 0.0;
+    // Scale factor: 0.0 == average, +/- 1.0 per stddev. Each component is
+    // measured from what a team of the same size would typically have, so a
+    // team of typical characters comes out around 0.0. Teams of fewer than
+    // two have nobody to (dis)agree with, so are always average.
+    pub fn harmony(&self, population: &population::Population) -> Harmony {
+        let mut harmony = Harmony{compatibility: 0.0, leadership: 0.0, leader: None};
+        let members = self.members.iter()
+            .filter_map(|cid| population.character_with_id(*cid))
+            .collect::<Vec<&character::Character>>();
+        if members.len() < 2 {
+            return harmony;
+        }
+
+        // Trait matching: charismatic pairs get along, while pairs whose work
+        // ethics are further apart than usual resent each other.
+        let average_charisma = character::Trait::CHARISMA.definition().average_rating().value as f32;
+        // The mean absolute difference of two normally distributed values.
+        let expected_work_ethic_gap = 2.0 * character::Trait::WORK_ETHIC.definition().value_stddev() / std::f32::consts::PI.sqrt();
+        let mut num_pairs = 0;
+        for (i, a) in members.iter().enumerate() {
+            for b in &members[i + 1..] {
+                let charisma = (a.get_trait_value(character::Trait::CHARISMA) + b.get_trait_value(character::Trait::CHARISMA)) as f32 / 2.0;
+                let work_ethic_gap = (a.get_trait_value(character::Trait::WORK_ETHIC) - b.get_trait_value(character::Trait::WORK_ETHIC)).abs() as f32;
+                harmony.compatibility += (charisma - average_charisma) / TRAIT_STDDEV + (expected_work_ethic_gap - work_ethic_gap) / TRAIT_STDDEV;
+                num_pairs += 1;
+            }
+        }
+        harmony.compatibility = (harmony.compatibility / num_pairs as f32).clamp(-1.0, 1.0);
+
+        // Leadership: a team with a designated leader is neither led nor
+        // leaderless as far as harmony goes. Otherwise it's down to how the
+        // member with the most of it compares to the best of a typical team
+        // this size: better, and they lead; worse, and the team is leaderless.
+        if self.leader.is_some() {
+            return harmony;
+        }
+        let most_leadership = members.iter()
+            .max_by_key(|c| (c.get_trait_value(character::Trait::LEADERSHIP), std::cmp::Reverse(c.id())))
+            .expect("team has members");
+        let def = character::Trait::LEADERSHIP.definition();
+        let expected_best = def.average_rating().value as f32 + expected_max(members.len()) * def.value_stddev();
+        let leadership = most_leadership.get_trait_value(character::Trait::LEADERSHIP) as f32;
+        harmony.leadership = ((leadership - expected_best) / TRAIT_STDDEV).clamp(-1.0, 1.0);
+        if harmony.leadership >= 0.0 {
+            harmony.leader = Some(most_leadership.id());
+        }
+        return harmony;
     }
 
     pub fn advance_turn(&self) {
 
     }
 }
+
+// The expected largest of 'n' standard normal samples, i.e. the integral of
+// x * n * pdf(x) * cdf(x)^(n - 1), worked out numerically.
+fn expected_max(n: usize) -> f32 {
+    const STEP: f64 = 0.01;
+    let mut cdf: f64 = 0.0;
+    let mut expected = 0.0;
+    let mut x: f64 = -8.0;
+    while x < 8.0 {
+        let pdf = (-x * x / 2.0).exp() / (2.0 * std::f64::consts::PI).sqrt();
+        cdf += pdf * STEP;
+        expected += x * n as f64 * pdf * cdf.powi(n as i32 - 1) * STEP;
+        x += STEP;
+    }
+    return expected as f32;
+}
+
+#[cfg(test)]
+mod team_tests {
+    use super::Team;
+    use crate::character::{Character, CharacterId, Trait};
+    use crate::population::Population;
+    use crate::types;
+    use crate::workforce::Job;
+
+    #[test]
+    fn harmony() {
+        let mut rng = types::new_rng(5);
+        let mut population = Population::new(
+            (0..3).map(|i| Character::new_random(CharacterId(i), &mut rng)).collect());
+        let average_charisma = Trait::CHARISMA.definition().average_rating().value;
+        for (cid, leadership) in vec![(CharacterId(0), 30), (CharacterId(1), 35), (CharacterId(2), 70)] {
+            let c = population.mut_character_with_id(cid).unwrap();
+            c.mut_trait(Trait::CHARISMA).value = average_charisma;
+            c.mut_trait(Trait::WORK_ETHIC).value = 50;
+            c.mut_trait(Trait::LEADERSHIP).value = leadership;
+        }
+
+        let single = Team::new_with_ids(maplit::hashset!{CharacterId(2)});
+        assert_eq!(0.0, single.harmony(&population).total());
+
+        // Work ethics closer than usual help; nobody stands out as a leader.
+        let pair = Team::new_with_ids(maplit::hashset!{CharacterId(0), CharacterId(1)});
+        let harmony = pair.harmony(&population);
+        assert_eq!(None, harmony.leader);
+        assert!(harmony.compatibility > 0.5, "{:?}", harmony);
+        assert!(harmony.leadership < 0.0, "{:?}", harmony);
+
+        // Working together doesn't help; that's cotenure's job.
+        for _ in 0..10 {
+            population.mut_rapport_tracker().inc_turns_on_same_team(Job::FARMER, &CharacterId(0), &CharacterId(1));
+        }
+        assert_eq!(harmony, pair.harmony(&population));
+
+        population.mut_character_with_id(CharacterId(1)).unwrap().mut_trait(Trait::WORK_ETHIC).value = 90;
+        let harmony = pair.harmony(&population);
+        assert_eq!(-1.0, harmony.compatibility);
+
+        let led = Team::new_with_ids(maplit::hashset!{CharacterId(0), CharacterId(2)});
+        let harmony = led.harmony(&population);
+        assert_eq!(Some(CharacterId(2)), harmony.leader);
+        assert_eq!(1.0, harmony.leadership);
+    }
+
+    #[test]
+    fn typical_harmony() {
+        // Teams of randomly generated characters are about average, whatever
+        // their size.
+        let mut rng = types::new_rng(0);
+        for size in 2..6 {
+            let teams = 500;
+            let total = (0..teams).map(|_| {
+                let population = Population::new(
+                    (0..size).map(|i| Character::new_random(CharacterId(i), &mut rng)).collect());
+                let team = Team::new_with_ids((0..size).map(CharacterId).collect());
+                return team.harmony(&population).total();
+            }).sum::<f32>();
+            let average = total / teams as f32;
+            assert!(average.abs() < 0.1, "teams of {} average {}", size, average);
+        }
+    }
+
+    #[test]
+    fn leader() {
+        let mut rng = types::new_rng(5);
+        let mut population = Population::new(
+            (0..3).map(|i| Character::new_random(CharacterId(i), &mut rng)).collect());
+        population.mut_character_with_id(CharacterId(0)).unwrap().mut_trait(Trait::LEADERSHIP).value = 30;
+        population.mut_character_with_id(CharacterId(1)).unwrap().mut_trait(Trait::LEADERSHIP).value = 80;
+
+        let mut team = Team::new_with_ids(maplit::hashset!{CharacterId(0), CharacterId(1)});
+        assert!(team.set_leader(Some(CharacterId(2))).is_err());
+        assert_eq!(Some(CharacterId(1)), team.harmony(&population).leader);
+
+        assert_eq!(1.0, team.harmony(&population).leadership);
+
+        // Designating a leader takes leadership out of harmony's hands,
+        // however (un)suited they are.
+        team.set_leader(Some(CharacterId(0))).expect("set_leader");
+        let harmony = team.harmony(&population);
+        assert_eq!(None, harmony.leader);
+        assert_eq!(0.0, harmony.leadership);
+
+        team.remove(&CharacterId(0));
+        assert_eq!(None, team.leader());
+    }
+}
@@ -0,0 +1,199 @@
+use super::character;
+use super::workforce;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+// The traits every game has, in the order they're defined in. Other traits
+// can be added, and these ones redefined, with load().
+const DEFAULT_TRAITS: &str = include_str!("../../data/traits.json");
+const BUILT_IN_TRAITS: [&str; 8] = [
+    "Intelligence", "Strength", "WorkEthic", "Charisma", "Dexterity", "Endurance", "Perception", "Leadership",
+];
+
+// A character trait, e.g. Trait::STRENGTH. Saved by name, so that save files
+// don't depend on the order traits were defined in.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Trait(usize);
+
+impl Trait {
+    pub const INTELLIGENCE: Trait = Trait(0);
+    pub const STRENGTH: Trait = Trait(1);
+    pub const WORK_ETHIC: Trait = Trait(2);
+    pub const CHARISMA: Trait = Trait(3);
+    pub const DEXTERITY: Trait = Trait(4);
+    pub const ENDURANCE: Trait = Trait(5);
+    pub const PERCEPTION: Trait = Trait(6);
+    pub const LEADERSHIP: Trait = Trait(7);
+
+    // All the defined traits, always in the same order.
+    pub fn iter() -> impl std::iter::Iterator<Item=Trait> {
+        return (0..definitions().read().expect("trait definitions").len()).map(Trait);
+    }
+
+    pub fn by_name(name: &str) -> Option<Trait> {
+        return definitions().read().expect("trait definitions").iter().position(|d| d.name == name).map(Trait);
+    }
+
+    pub fn definition(&self) -> TraitDefinition {
+        return definitions().read().expect("trait definitions")[self.0].clone();
+    }
+
+    pub fn name(&self) -> String {
+        return self.definition().name;
+    }
+
+    pub fn string3(&self) -> String {
+        return self.definition().code;
+    }
+}
+
+impl Serialize for Trait {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.serialize_str(&self.name());
+    }
+}
+
+impl<'de> Deserialize<'de> for Trait {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Trait, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        return Trait::by_name(&name).ok_or_else(|| serde::de::Error::custom(
+            format!("unknown trait {:?}; is its definition loaded?", name)));
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TraitDefinition {
+    pub name: String,
+    // Three letters, for compact displays.
+    pub code: String,
+
+    // New characters' capacities are drawn from a normal distribution, and
+    // their values start a normally distributed 'headroom' below that.
+    pub capacity_mean: i32,
+    pub capacity_stddev: f32,
+    pub headroom_mean: i32,
+    pub headroom_stddev: f32,
+
+    // How much each point of the trait above (or below) 50 boosts a worker's
+    // production, per 10 points (1 stddev), by job.
+    pub job_weights: std::collections::HashMap<workforce::Job, f32>,
+    // How much a designated team leader's rating in the trait boosts the
+    // whole team's production, in the same units, but measured from the
+    // trait's average_rating() rather than 50.
+    #[serde(default)]
+    pub leader_weight: f32,
+}
+
+impl TraitDefinition {
+    // What a character with no particular aptitude for the trait would have.
+    pub fn average_rating(&self) -> character::TraitRating {
+        return character::TraitRating{
+            capacity: self.capacity_mean,
+            value: std::cmp::max(0, self.capacity_mean - self.headroom_mean),
+        };
+    }
+
+    // How far new characters' values typically are from average_rating()'s.
+    pub fn value_stddev(&self) -> f32 {
+        return (self.capacity_stddev.powi(2) + self.headroom_stddev.powi(2)).sqrt();
+    }
+}
+
+fn definitions() -> &'static std::sync::RwLock<Vec<TraitDefinition>> {
+    static DEFINITIONS: std::sync::OnceLock<std::sync::RwLock<Vec<TraitDefinition>>> = std::sync::OnceLock::new();
+    return DEFINITIONS.get_or_init(|| {
+        let mut defs = vec![];
+        merge(&mut defs, parse(DEFAULT_TRAITS).expect("parsing built-in traits")).expect("built-in traits");
+        for (i, name) in BUILT_IN_TRAITS.iter().enumerate() {
+            assert_eq!(Some(*name), defs.get(i).map(|d| d.name.as_str()), "built-in trait {} out of order", i);
+        }
+        return std::sync::RwLock::new(defs);
+    });
+}
+
+// The built-in traits as they were originally defined, whatever load() has
+// done to them since.
+pub fn built_in_definitions() -> Vec<TraitDefinition> {
+    return parse(DEFAULT_TRAITS).expect("parsing built-in traits").into_iter()
+        .filter(|d| BUILT_IN_TRAITS.contains(&d.name.as_str())).collect();
+}
+
+pub fn parse(json: &str) -> anyhow::Result<Vec<TraitDefinition>> {
+    return Ok(serde_json::from_str(json)?);
+}
+
+// Traits already in 'into' are redefined in place; new ones are added at the
+// end.
+fn merge(into: &mut Vec<TraitDefinition>, defs: Vec<TraitDefinition>) -> anyhow::Result<()> {
+    for def in defs {
+        if def.code.chars().count() != 3 {
+            return Err(anyhow::anyhow!("Trait {} has code {:?}, which isn't three letters", def.name, def.code));
+        }
+        if into.iter().any(|d| d.code == def.code && d.name != def.name) {
+            return Err(anyhow::anyhow!("Trait {} has the same code as another trait: {}", def.name, def.code));
+        }
+        match into.iter().position(|d| d.name == def.name) {
+            Some(i) => into[i] = def,
+            None => into.push(def),
+        }
+    }
+    return Ok(());
+}
+
+// Adds (or redefines) traits from a JSON file in the format of
+// data/traits.json. Affects every game from then on, so should be done before
+// any are started; a save that uses a trait can only be restored once it has
+// been defined.
+pub fn load<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<()> {
+    let json = std::fs::read_to_string(path.as_ref()).with_context(|| format!("Reading {:?}", path.as_ref()))?;
+    let defs = parse(&json).with_context(|| format!("Parsing {:?}", path.as_ref()))?;
+    let mut current = definitions().read().expect("trait definitions").clone();
+    merge(&mut current, defs)?;
+    *definitions().write().expect("trait definitions") = current;
+    return Ok(());
+}
+
+// The traits that make a good team leader, and how much.
+pub fn leader_weights() -> Vec<(Trait, f32)> {
+    return Trait::iter().map(|t| (t, t.definition().leader_weight)).filter(|&(_, w)| w != 0.0).collect();
+}
+
+// The traits that matter for 'job', and how much.
+pub fn job_weights(job: workforce::Job) -> Vec<(Trait, f32)> {
+    return Trait::iter().filter_map(|t| t.definition().job_weights.get(&job).map(|&w| (t, w))).collect();
+}
+
+#[cfg(test)]
+mod traits_tests {
+    use super::Trait;
+    use crate::workforce::Job;
+
+    #[test]
+    fn definitions() {
+        assert_eq!("WOR", Trait::WORK_ETHIC.string3());
+        assert_eq!(Some(Trait::LEADERSHIP), Trait::by_name("Leadership"));
+        assert_eq!("\"Dexterity\"", serde_json::to_string(&Trait::DEXTERITY).unwrap());
+        assert!(serde_json::from_str::<Trait>("\"Luck\"").is_err());
+        assert!(super::job_weights(Job::BUILDER).contains(&(Trait::STRENGTH, 0.1)));
+        assert_eq!(vec![(Trait::CHARISMA, 0.025), (Trait::LEADERSHIP, 0.05)], super::leader_weights());
+        assert_eq!(super::BUILT_IN_TRAITS.to_vec(),
+                   super::built_in_definitions().iter().map(|d| d.name.as_str()).collect::<Vec<&str>>());
+
+        let mut defs = super::parse(super::DEFAULT_TRAITS).expect("parse");
+        let custom = super::parse(r#"[
+            {"name": "Strength", "code": "STR", "capacity_mean": 70, "capacity_stddev": 5.0,
+             "headroom_mean": 10, "headroom_stddev": 5.0, "job_weights": {}},
+            {"name": "Luck", "code": "LCK", "capacity_mean": 50, "capacity_stddev": 20.0,
+             "headroom_mean": 0, "headroom_stddev": 0.0, "job_weights": {"FARMER": 0.2}}
+        ]"#).expect("parse");
+        super::merge(&mut defs, custom).expect("merge");
+        assert_eq!(9, defs.len());
+        assert_eq!(70, defs[Trait::STRENGTH.0].capacity_mean);
+        assert_eq!("Luck", defs[8].name);
+
+        let clash = super::parse(r#"[{"name": "Lore", "code": "LCK", "capacity_mean": 50,
+            "capacity_stddev": 10.0, "headroom_mean": 10, "headroom_stddev": 5.0, "job_weights": {}}]"#).expect("parse");
+        assert!(super::merge(&mut defs, clash).is_err());
+    }
+}
@@ -1,6 +1,15 @@
 use std;
+use rand::SeedableRng;
 use serde::{Deserialize, Serialize};
 
+// All randomness in the simulation comes from a GameRng owned by the game
+// state, so that a game started from the same seed plays out identically.
+pub type GameRng = rand_pcg::Pcg32;
+
+pub fn new_rng(seed: u64) -> GameRng {
+    return GameRng::seed_from_u64(seed);
+}
+
 #[derive(Eq, Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct Millis {
     rep: i64,
@@ -18,6 +27,10 @@ impl Millis {
     pub fn from_i32(v: i32) -> Millis {
         return Millis{rep: 1000 * (v as i64)};
     }
+
+    pub fn to_f32(&self) -> f32 {
+        return self.rep as f32 / 1000.0;
+    }
 }
 
 impl std::cmp::PartialEq for Millis {
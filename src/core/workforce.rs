@@ -54,6 +54,10 @@ impl Workforce {
         return self.teams.get_mut(job).ok_or_else(|| anyhow!("Unknown team: {:?}", job));
     }
 
+    pub fn set_leader(&mut self, job: Job, leader: Option<character::CharacterId>) -> anyhow::Result<()> {
+        return self.mut_team(&job)?.set_leader(leader);
+    }
+
     fn unset_old_assignment(&mut self, char_id: character::CharacterId, job: Job) {
         match self.assignments.get(&char_id).map(|jobref| *jobref) {
             Some(old_job) => {
@@ -70,6 +74,9 @@ impl Workforce {
     }
 
     pub fn assign(&mut self, char_id: character::CharacterId, job: Job) -> anyhow::Result<()> {
+        if !self.contains(char_id) {
+            return Err(anyhow!("Character {} isn't part of the workforce", char_id));
+        }
         self.unset_old_assignment(char_id, job);
         self.mut_team(&job)?.add(&char_id);
         self.assignments.insert(char_id, job);
@@ -77,11 +84,34 @@ impl Workforce {
     }
 
     pub fn unassign(&mut self, char_id: character::CharacterId, job: Job) -> anyhow::Result<()> {
+        if !self.contains(char_id) {
+            return Err(anyhow!("Character {} isn't part of the workforce", char_id));
+        }
         self.unset_old_assignment(char_id, job);
         self.unassigned.add(&char_id);
         return Ok(());
     }
 
+    // Takes the character off whichever team they're on, e.g. because they've
+    // left the castle.
+    pub fn remove(&mut self, char_id: character::CharacterId) {
+        match self.assignments.remove(&char_id) {
+            Some(job) => self.mut_team(&job).expect("assigned to unknown team").remove(&char_id),
+            // Children aren't part of the workforce yet.
+            None if self.unassigned.contains(&char_id) => self.unassigned.remove(&char_id),
+            None => {},
+        }
+    }
+
+    pub fn contains(&self, char_id: character::CharacterId) -> bool {
+        return self.assignments.contains_key(&char_id) || self.unassigned.contains(&char_id);
+    }
+
+    // None if the character is unassigned (or unknown).
+    pub fn job_of(&self, char_id: character::CharacterId) -> Option<Job> {
+        return self.assignments.get(&char_id).cloned();
+    }
+
     pub fn farmers(&self) -> &team::Team {
         return self.team(&Job::FARMER).expect("FARMERS");
     }
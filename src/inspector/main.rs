@@ -0,0 +1,200 @@
+use simcastle_core;
+use simcastle_core::gamestate::{GameStateT, MutationT, UserCommand};
+use simcastle_core::statemachine::LogEntry;
+
+#[path = "../common/flags.rs"]
+mod flags;
+use flags::parse_flag;
+
+// Which entries to print. Everything is printed by default.
+struct Filter {
+    from_turn: Option<i32>,
+    to_turn: Option<i32>,
+    kinds: Option<Vec<String>>,
+    character: Option<simcastle_core::character::CharacterId>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &LogEntry<GameStateT, MutationT>, turn: Option<i32>) -> bool {
+        if let Some(turn) = turn {
+            if self.from_turn.map(|from| turn < from).unwrap_or(false) ||
+                self.to_turn.map(|to| turn > to).unwrap_or(false) {
+                return false;
+            }
+        }
+        if let Some(kinds) = &self.kinds {
+            if !kinds.iter().any(|k| k.eq_ignore_ascii_case(kind(entry))) {
+                return false;
+            }
+        }
+        if let Some(cid) = self.character {
+            let involved = match entry {
+                LogEntry::Delta(m) => character_of(m) == Some(cid),
+                _ => false,
+            };
+            if !involved {
+                return false;
+            }
+        }
+        return true;
+    }
+}
+
+fn kind(entry: &LogEntry<GameStateT, MutationT>) -> &'static str {
+    match entry {
+        LogEntry::Header(_) => "Header",
+        LogEntry::Checkpoint(_) => "Checkpoint",
+        LogEntry::Snapshot(_) => "Snapshot",
+        LogEntry::Revert => "Revert",
+        LogEntry::Delta(MutationT::EndTurn{..}) => "EndTurn",
+        LogEntry::Delta(MutationT::UserCommand{..}) => "UserCommand",
+        LogEntry::Delta(MutationT::UpdateCharacter{..}) => "UpdateCharacter",
+        LogEntry::Delta(MutationT::CompleteInfrastructure{..}) => "CompleteInfrastructure",
+        LogEntry::Delta(MutationT::RemoveCharacter{..}) => "RemoveCharacter",
+        LogEntry::Delta(MutationT::FormHousehold{..}) => "FormHousehold",
+        LogEntry::Delta(MutationT::Birth{..}) => "Birth",
+        LogEntry::Delta(MutationT::ComeOfAge{..}) => "ComeOfAge",
+    }
+}
+
+// The character a mutation is about, if any.
+fn character_of(m: &MutationT) -> Option<simcastle_core::character::CharacterId> {
+    match m {
+        MutationT::UserCommand{cmd: UserCommand::AssignToTeam{cid, ..}} => Some(*cid),
+        MutationT::UserCommand{cmd: UserCommand::AddCharacter{character}} => Some(character.id()),
+        MutationT::UserCommand{cmd: UserCommand::SetTeamLeader{cid, ..}} => *cid,
+        MutationT::UpdateCharacter{character_delta} => Some(character_delta.id),
+        MutationT::RemoveCharacter{cid, ..} => Some(*cid),
+        MutationT::Birth{character} => Some(character.id()),
+        MutationT::ComeOfAge{cid} => Some(*cid),
+        _ => None,
+    }
+}
+
+fn summary(entry: &LogEntry<GameStateT, MutationT>) -> String {
+    match entry {
+        LogEntry::Header(header) => format!("schema version {}", header.version),
+        LogEntry::Checkpoint(state) | LogEntry::Snapshot(state) =>
+            format!("turn {}, food {}, population {}", state.turn, state.food, state.population.characters().len()),
+        LogEntry::Revert => "undo the last delta".to_string(),
+        LogEntry::Delta(MutationT::EndTurn{builder_accumulation, food, turns_starving, ..}) =>
+            format!("food -> {}, build progress -> {}, turns starving -> {}", food, builder_accumulation, turns_starving),
+        LogEntry::Delta(MutationT::UserCommand{cmd}) => match cmd {
+            UserCommand::AssignToTeam{cid, job} => format!("assign {} to {:?}", cid, job),
+            UserCommand::AddCharacter{character} => format!("add {}", character.full_debug_string()),
+            UserCommand::AddToBuildQueue{infra} => format!("queue {:?}", infra),
+            UserCommand::SetTeamLeader{job, cid: Some(cid)} => format!("make {} leader of {:?}", cid, job),
+            UserCommand::SetTeamLeader{job, cid: None} => format!("remove leader of {:?}", job),
+        },
+        LogEntry::Delta(MutationT::UpdateCharacter{character_delta}) => {
+            let mut changes = character_delta.changed_trait_values.iter()
+                .map(|(t, v)| format!("{} -> {}", t.string3(), v))
+                .collect::<Vec<String>>();
+            changes.extend(character_delta.changed_trait_capacities.iter()
+                .map(|(t, v)| format!("{} capacity -> {}", t.string3(), v)));
+            changes.sort();
+            if let Some(health) = character_delta.health {
+                changes.push(format!("health -> {}", health));
+            }
+            if let Some(morale) = character_delta.morale {
+                changes.push(format!("morale -> {}", morale));
+            }
+            if let Some(age) = character_delta.age {
+                changes.push(format!("age -> {}", age));
+            }
+            format!("character {}: {}", character_delta.id, changes.join(", "))
+        },
+        LogEntry::Delta(MutationT::CompleteInfrastructure{infra}) => format!("completed {:?}", infra),
+        LogEntry::Delta(MutationT::RemoveCharacter{cid, reason}) => format!("remove {} ({:?})", cid, reason),
+        LogEntry::Delta(MutationT::FormHousehold{household}) =>
+            format!("household of {} and {}", household.a, household.b),
+        LogEntry::Delta(MutationT::Birth{character}) => format!("born {}", character.full_debug_string()),
+        LogEntry::Delta(MutationT::ComeOfAge{cid}) => format!("{} joins the workforce", cid),
+    }
+}
+
+fn main() {
+    let usage = "usage: simcastle-inspector <save file> [--from-turn N] [--to-turn N] \
+                 [--kind EndTurn,UserCommand,...] [--character ID]\n       \
+                 simcastle-inspector <save file> --diff FROM,TO [--json]\n       \
+                 simcastle-inspector <save file> --verify";
+    let save_path = match std::env::args().nth(1) {
+        Some(path) if !path.starts_with("--") => path,
+        _ => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        },
+    };
+    if let Some(turns) = parse_flag::<String>("--diff") {
+        let turns = turns.split(',').map(|t| t.parse::<i32>()).collect::<Result<Vec<i32>, _>>();
+        let (from, to) = match turns.as_ref().map(|t| t.as_slice()) {
+            Ok(&[from, to]) => (from, to),
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            },
+        };
+        let diff = simcastle_core::gamestate::GameState::diff_turns(&save_path, from, to)
+            .unwrap_or_else(|err| {
+                eprintln!("Couldn't diff {}: {:?}", save_path, err);
+                std::process::exit(1);
+            });
+        if std::env::args().any(|a| a == "--json") {
+            println!("{}", diff.to_json().expect("to_json"));
+        } else {
+            print!("{}", diff);
+        }
+        return;
+    }
+
+    if std::env::args().any(|a| a == "--verify") {
+        match simcastle_core::gamestate::GameState::verify_save(&save_path) {
+            Ok(verification) => {
+                println!("Checked replay against {} snapshots and checkpoints", verification.states_checked);
+                if let Some(divergence) = verification.divergence {
+                    println!("{}", divergence);
+                    std::process::exit(1);
+                }
+            },
+            Err(err) => {
+                eprintln!("Couldn't verify {}: {:?}", save_path, err);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    let filter = Filter{
+        from_turn: parse_flag::<i32>("--from-turn"),
+        to_turn: parse_flag::<i32>("--to-turn"),
+        kinds: parse_flag::<String>("--kind").map(|k| k.split(',').map(|s| s.to_string()).collect()),
+        character: parse_flag::<i64>("--character").map(simcastle_core::character::CharacterId),
+    };
+
+    let report = simcastle_core::gamestate::GameState::inspect_save(&save_path, &mut |i, entry, state| {
+        // A checkpoint's turn is its own; a delta's is that of the state it
+        // was applied to.
+        let turn = match entry {
+            LogEntry::Checkpoint(cp) | LogEntry::Snapshot(cp) => Some(cp.turn),
+            _ => state.map(|s| s.turn),
+        };
+        if !filter.matches(entry, turn) {
+            return;
+        }
+        let turn_str = turn.map(|t| t.to_string()).unwrap_or("-".to_string());
+        println!("{:>6} turn {:>4}  {:<22} {}", i, turn_str, kind(entry), summary(entry));
+    });
+    match report {
+        Ok(report) => {
+            println!("{} entries ({:?})", report.entries_read, report.format);
+            if report.torn_entries_dropped > 0 {
+                println!("{} torn entries ({} bytes) at the end of the log were skipped",
+                         report.torn_entries_dropped, report.torn_bytes_dropped);
+            }
+        },
+        Err(err) => {
+            eprintln!("Couldn't read {}: {:?}", save_path, err);
+            std::process::exit(1);
+        },
+    }
+}